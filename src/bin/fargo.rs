@@ -4,12 +4,12 @@ extern crate failure;
 extern crate fargo;
 extern crate itertools;
 
-use fargo::run;
+use fargo::{exit_code, run};
 use itertools::Itertools;
 
 fn main() {
     if let Err(ref e) = run() {
-        println!("error: {}", e.causes().join(", "));
-        ::std::process::exit(1);
+        println!("error: {}", e.iter_chain().join(", "));
+        ::std::process::exit(exit_code(e));
     }
 }
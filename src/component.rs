@@ -0,0 +1,161 @@
+// Copyright 2018 The Fuchsia Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use failure::{Error, ResultExt};
+use serde_json;
+
+/// Sandbox features a component manifest may request.
+static SANDBOX_FEATURES: &[&str] = &[
+    "build-info",
+    "config-data",
+    "deprecated-all-services",
+    "hub",
+    "introspection",
+    "isolated-persistent-storage",
+    "isolated-temp",
+    "persistent-storage",
+    "root-ssl-certificates",
+    "shell",
+    "system-temp",
+    "vulkan",
+];
+
+/// The `[package.metadata.fargo.component]` table of a package, describing the
+/// sandbox and arguments of the component fargo packages each of its binaries as.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ComponentConfig {
+    #[serde(default)]
+    pub services: Vec<String>,
+    #[serde(default)]
+    pub features: Vec<String>,
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+impl ComponentConfig {
+    /// Reads the component table from the `metadata` of a package, as reported by
+    /// `cargo metadata`.
+    pub fn from_package_metadata(
+        metadata: Option<&serde_json::Value>,
+    ) -> Result<ComponentConfig, Error> {
+        let component = metadata
+            .and_then(|metadata| metadata.get("fargo"))
+            .and_then(|fargo| fargo.get("component"));
+        let config: ComponentConfig = match component {
+            Some(component) => serde_json::from_value(component.clone())
+                .context("invalid [package.metadata.fargo.component] table")?,
+            None => ComponentConfig::default(),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        for service in &self.services {
+            let valid = service.split('.').count() > 1 && service.split('.').all(|part| {
+                !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            });
+            if !valid {
+                bail!("'{}' is not a service name like 'fuchsia.sys.Launcher'", service);
+            }
+        }
+        for feature in &self.features {
+            if !SANDBOX_FEATURES.contains(&feature.as_str()) {
+                bail!(
+                    "unknown sandbox feature '{}', expected one of: {}",
+                    feature,
+                    SANDBOX_FEATURES.join(", ")
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct Program<'a> {
+    binary: &'a str,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    args: &'a [String],
+}
+
+#[derive(Serialize)]
+struct Sandbox<'a> {
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    services: &'a [String],
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    features: &'a [String],
+}
+
+#[derive(Serialize)]
+struct ComponentManifest<'a> {
+    program: Program<'a>,
+    sandbox: Sandbox<'a>,
+}
+
+/// Returns the `.cmx` manifest of a component running `binary_path` from its package.
+pub fn component_manifest(binary_path: &str, config: &ComponentConfig) -> Result<String, Error> {
+    let manifest = ComponentManifest {
+        program: Program {
+            binary: binary_path,
+            args: &config.args,
+        },
+        sandbox: Sandbox {
+            services: &config.services,
+            features: &config.features,
+        },
+    };
+    Ok(serde_json::to_string_pretty(&manifest)? + "\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use component::{ComponentConfig, component_manifest};
+    use serde_json;
+
+    #[test]
+    fn test_component_manifest() {
+        let metadata = serde_json::from_str(
+            r#"{"fargo": {"component": {
+                "services": ["fuchsia.sys.Launcher"],
+                "features": ["persistent-storage"],
+                "args": ["--verbose"]
+            }}}"#,
+        ).unwrap();
+        let config = ComponentConfig::from_package_metadata(Some(&metadata)).unwrap();
+        let manifest: serde_json::Value =
+            serde_json::from_str(&component_manifest("bin/app", &config).unwrap()).unwrap();
+        assert_eq!(
+            manifest,
+            serde_json::from_str::<serde_json::Value>(
+                r#"{
+                    "program": {"binary": "bin/app", "args": ["--verbose"]},
+                    "sandbox": {
+                        "services": ["fuchsia.sys.Launcher"],
+                        "features": ["persistent-storage"]
+                    }
+                }"#
+            ).unwrap()
+        );
+
+        let config = ComponentConfig::from_package_metadata(None).unwrap();
+        assert_eq!(
+            component_manifest("bin/app", &config).unwrap(),
+            "{\n  \"program\": {\n    \"binary\": \"bin/app\"\n  },\n  \"sandbox\": {}\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_invalid_component_config() {
+        for component in &[
+            r#"{"fargo": {"component": {"services": ["Launcher"]}}}"#,
+            r#"{"fargo": {"component": {"features": ["everything"]}}}"#,
+            r#"{"fargo": {"component": {"sevrices": []}}}"#,
+        ] {
+            let metadata = serde_json::from_str(component).unwrap();
+            assert!(ComponentConfig::from_package_metadata(Some(&metadata)).is_err());
+        }
+    }
+}
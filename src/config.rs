@@ -0,0 +1,158 @@
+// Copyright 2018 The Fuchsia Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use failure::{Error, ResultExt};
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use toml;
+use toml::Value as Toml;
+
+/// Default settings for fargo commands. Values are loaded from the user-level
+/// `~/.fargo/config.toml`, then from the project's `[package.metadata.fargo]` table
+/// and finally from a `Fargo.toml` next to the project's `Cargo.toml`, each layer
+/// overriding the previous one. Command line flags override all of them.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct FargoConfig {
+    pub device_name: Option<String>,
+    pub device_addr: Option<String>,
+    pub debug_os: Option<bool>,
+    pub target_cpu: Option<String>,
+    pub release: Option<bool>,
+    pub set_root_view: Option<bool>,
+    pub test_args: Option<String>,
+    pub runner: Option<String>,
+    pub native_ssh: Option<bool>,
+    /// Files, relative to the package root, that `fargo package` installs under `data/`.
+    pub resources: Option<Vec<String>>,
+}
+
+impl FargoConfig {
+    /// Loads the merged configuration for the project containing the current directory.
+    pub fn load() -> Result<FargoConfig, Error> {
+        let mut config = FargoConfig::default();
+        if let Some(user_config_path) = user_config_path() {
+            if user_config_path.exists() {
+                config = config.merge(parse_config_file(&user_config_path)?);
+            }
+        }
+        if let Some(project_root) = find_project_root(&env::current_dir()?) {
+            let cargo_toml = read_toml(&project_root.join("Cargo.toml"))?;
+            if let Some(metadata_config) = metadata_config(&cargo_toml)? {
+                config = config.merge(metadata_config);
+            }
+            let fargo_toml_path = project_root.join("Fargo.toml");
+            if fargo_toml_path.exists() {
+                config = config.merge(parse_config_file(&fargo_toml_path)?);
+            }
+        }
+        Ok(config)
+    }
+
+    /// Returns a configuration where every value set in `other` replaces the one in `self`.
+    pub fn merge(self, other: FargoConfig) -> FargoConfig {
+        FargoConfig {
+            device_name: other.device_name.or(self.device_name),
+            device_addr: other.device_addr.or(self.device_addr),
+            debug_os: other.debug_os.or(self.debug_os),
+            target_cpu: other.target_cpu.or(self.target_cpu),
+            release: other.release.or(self.release),
+            set_root_view: other.set_root_view.or(self.set_root_view),
+            test_args: other.test_args.or(self.test_args),
+            runner: other.runner.or(self.runner),
+            native_ssh: other.native_ssh.or(self.native_ssh),
+            resources: other.resources.or(self.resources),
+        }
+    }
+
+    pub fn runner_path(&self) -> Option<PathBuf> {
+        self.runner.as_ref().map(PathBuf::from)
+    }
+}
+
+fn user_config_path() -> Option<PathBuf> {
+    env::var("HOME").ok().map(|home| PathBuf::from(home).join(".fargo").join("config.toml"))
+}
+
+fn find_project_root(start: &Path) -> Option<PathBuf> {
+    let mut path = start.to_path_buf();
+    loop {
+        if path.join("Cargo.toml").exists() {
+            return Some(path);
+        }
+        path = path.parent()?.to_path_buf();
+    }
+}
+
+fn read_toml(path: &Path) -> Result<Toml, Error> {
+    let mut contents = String::new();
+    File::open(path)
+        .context(format!("unable to open {:?}", path))?
+        .read_to_string(&mut contents)?;
+    Ok(toml::from_str(&contents).context(format!("unable to parse {:?}", path))?)
+}
+
+fn parse_config_file(path: &Path) -> Result<FargoConfig, Error> {
+    Ok(read_toml(path)?.try_into().context(format!("invalid fargo configuration in {:?}", path))?)
+}
+
+fn metadata_config(cargo_toml: &Toml) -> Result<Option<FargoConfig>, Error> {
+    let fargo_table = cargo_toml
+        .get("package")
+        .and_then(|package| package.get("metadata"))
+        .and_then(|metadata| metadata.get("fargo"));
+    match fargo_table {
+        Some(table) => Ok(Some(
+            table.clone().try_into().context("invalid [package.metadata.fargo] table")?,
+        )),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::{FargoConfig, metadata_config};
+    use toml;
+
+    #[test]
+    fn test_metadata_config() {
+        let cargo_toml = toml::from_str(
+            r#"
+            [package]
+            name = "fargo-test"
+
+            [package.metadata.fargo]
+            device-name = "ivy-donut-grew-stoop"
+            release = true
+            "#,
+        ).unwrap();
+        let config = metadata_config(&cargo_toml).unwrap().unwrap();
+        assert_eq!(config.device_name, Some(String::from("ivy-donut-grew-stoop")));
+        assert_eq!(config.release, Some(true));
+        assert_eq!(config.debug_os, None);
+
+        let cargo_toml = toml::from_str("[package]\nname = \"fargo-test\"\n").unwrap();
+        assert_eq!(metadata_config(&cargo_toml).unwrap(), None);
+    }
+
+    #[test]
+    fn test_merge() {
+        let user = FargoConfig {
+            device_name: Some(String::from("user-device")),
+            test_args: Some(String::from("--nocapture")),
+            ..FargoConfig::default()
+        };
+        let project = FargoConfig {
+            device_name: Some(String::from("project-device")),
+            release: Some(false),
+            ..FargoConfig::default()
+        };
+        let merged = user.merge(project);
+        assert_eq!(merged.device_name, Some(String::from("project-device")));
+        assert_eq!(merged.test_args, Some(String::from("--nocapture")));
+        assert_eq!(merged.release, Some(false));
+    }
+}
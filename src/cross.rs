@@ -75,8 +75,9 @@ pub fn run_configure(
     let toolchain_bin_path = toolchain_path.join("bin");
 
     let common_c_flags = format!(
-        "--sysroot={} --target=x86_64-fuchsia -fPIC -I{}",
+        "--sysroot={} --target={}-fuchsia -fPIC -I{}",
         sysroot_path.to_str().unwrap(),
+        target_options.target_cpu_linker,
         cross_root.join("include").to_str().unwrap()
     );
 
@@ -89,11 +90,12 @@ pub fn run_configure(
     }
 
     let prefix = format!("--prefix={}", cross_root_str);
+    let host = format!("--host={}-fuchsia-elf", target_options.target_cpu_linker);
 
     let mut configure_args = vec![];
 
     if use_host {
-        configure_args.push("--host=x86_64-fuchsia-elf");
+        configure_args.push(&host);
     }
 
     configure_args.push(&prefix);
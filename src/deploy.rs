@@ -0,0 +1,201 @@
+// Copyright 2018 The Fuchsia Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use device::Transport;
+use failure::{Error, ResultExt};
+use libc;
+use sdk::TargetOptions;
+use serde_json;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Records the hash of every file fargo has copied to a device, so that copying a
+/// file that has not changed since can be skipped.
+#[derive(Debug, Default, PartialEq)]
+pub struct DeployManifest {
+    path: PathBuf,
+    deployed: BTreeMap<String, String>,
+}
+
+fn manifest_path(target_options: &TargetOptions) -> Result<PathBuf, Error> {
+    let home_value = env::var("HOME")?;
+    let device_key = target_options.device_key();
+    Ok(PathBuf::from(home_value).join(".fargo").join(format!("deployed-{}.json", device_key)))
+}
+
+impl DeployManifest {
+    pub fn load(target_options: &TargetOptions) -> Result<DeployManifest, Error> {
+        let path = manifest_path(target_options)?;
+        let deployed = match File::open(&path) {
+            // A manifest that can't be read only costs a copy, so start over with an empty one.
+            Ok(manifest_file) => serde_json::from_reader(manifest_file).unwrap_or_default(),
+            Err(_) => BTreeMap::new(),
+        };
+        Ok(DeployManifest { path, deployed })
+    }
+
+    /// Applies `change` to the manifest on disk and keeps a copy of the result. Several
+    /// fargo processes may deploy to the same device at once, so the manifest is
+    /// re-read while holding a lock and replaced by renaming a complete new file.
+    fn update<F>(&mut self, change: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut BTreeMap<String, String>),
+    {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let lock_path = self.path.with_extension("lock");
+        let lock_file =
+            File::create(&lock_path).context(format!("unable to create {:?}", lock_path))?;
+        if unsafe { libc::flock(lock_file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            bail!("unable to lock {:?}: {}", lock_path, io::Error::last_os_error());
+        }
+
+        let mut deployed = match File::open(&self.path) {
+            Ok(manifest_file) => serde_json::from_reader(manifest_file).unwrap_or_default(),
+            Err(_) => BTreeMap::new(),
+        };
+        change(&mut deployed);
+        let temp_path = self.path.with_extension(format!("json.{}", process::id()));
+        let manifest_file =
+            File::create(&temp_path).context(format!("unable to create {:?}", temp_path))?;
+        serde_json::to_writer_pretty(manifest_file, &deployed)?;
+        fs::rename(&temp_path, &self.path)
+            .context(format!("unable to replace {:?}", self.path))?;
+        self.deployed = deployed;
+        Ok(())
+    }
+
+    pub fn deployed_hash(&self, destination_path: &str) -> Option<&str> {
+        self.deployed.get(destination_path).map(String::as_str)
+    }
+
+    pub fn record(&mut self, destination_path: &str, hash: &str) -> Result<(), Error> {
+        self.update(|deployed| {
+            deployed.insert(destination_path.to_string(), hash.to_string());
+        })
+    }
+
+    /// Forgets a file that is no longer known to be on the device.
+    pub fn forget(&mut self, destination_path: &str) -> Result<(), Error> {
+        if !self.deployed.contains_key(destination_path) {
+            return Ok(());
+        }
+        self.update(|deployed| {
+            deployed.remove(destination_path);
+        })
+    }
+}
+
+pub fn sha256_file(path: &Path) -> Result<String, Error> {
+    let mut contents = vec![];
+    File::open(path)
+        .context(format!("unable to open {:?}", path))?
+        .read_to_end(&mut contents)?;
+    Ok(Sha256::digest(&contents).iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Returns the hash the device computes for `destination_path`, or `None` if the
+/// file is missing or can't be hashed.
+fn remote_hash(
+    transport: &mut dyn Transport,
+    destination_path: &str,
+) -> Result<Option<String>, Error> {
+    let mut output = String::new();
+    let exit_code = transport.run_with_output(
+        &format!("sha256sum {}", destination_path),
+        &mut |line| {
+            output.push_str(line);
+            output.push('\n');
+            Ok(())
+        },
+    )?;
+    if exit_code != 0 {
+        return Ok(None);
+    }
+    Ok(output.split_whitespace().next().map(str::to_lowercase))
+}
+
+/// Copies `source_path` to `destination_path` on the device unless the manifest
+/// says the same file was copied before and the device confirms it still has it.
+/// Returns whether the file was copied.
+pub fn deploy(
+    verbose: bool,
+    source_path: &Path,
+    destination_path: &str,
+    manifest: &mut DeployManifest,
+    transport: &mut dyn Transport,
+) -> Result<bool, Error> {
+    let hash = sha256_file(source_path)?;
+    if manifest.deployed_hash(destination_path) == Some(&hash) {
+        if remote_hash(transport, destination_path)?.as_ref() == Some(&hash) {
+            if verbose {
+                println!("{} is unchanged on the device", destination_path);
+            }
+            return Ok(false);
+        }
+        manifest.forget(destination_path)?;
+    }
+    eprintln!("copying {} to {}", source_path.to_string_lossy(), destination_path);
+    transport.copy_to_device(source_path, destination_path)?;
+    manifest.record(destination_path, &hash)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use deploy::{DeployManifest, deploy};
+    use std::env;
+    use std::fs;
+    use test_util::{FakeDevice, FakeRun};
+
+    #[test]
+    fn test_deploy() {
+        let dir = env::temp_dir().join(format!("fargo-deploy-test-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let source_path = dir.join("fargo_test");
+        fs::write(&source_path, b"fargo").unwrap();
+        let mut manifest = DeployManifest {
+            path: dir.join("deployed.json"),
+            ..DeployManifest::default()
+        };
+        let mut device = FakeDevice::default();
+
+        assert!(deploy(false, &source_path, "/tmp/fargo_test", &mut manifest, &mut device)
+            .unwrap());
+        let recorded = manifest.deployed_hash("/tmp/fargo_test").unwrap().to_string();
+        assert_eq!(recorded.len(), 64);
+
+        // Only skipped once the device confirms it has the same file.
+        device.push_run(FakeRun::Exit(String::new(), 1));
+        assert!(deploy(false, &source_path, "/tmp/fargo_test", &mut manifest, &mut device)
+            .unwrap());
+        device.push_run(FakeRun::Exit(format!("{}  /tmp/fargo_test", recorded), 0));
+        assert!(!deploy(false, &source_path, "/tmp/fargo_test", &mut manifest, &mut device)
+            .unwrap());
+        assert_eq!(device.commands(), vec!["sha256sum /tmp/fargo_test"; 2]);
+        assert_eq!(
+            device.copied_files(),
+            vec![(String::from("/tmp/fargo_test"), b"fargo".to_vec()); 2]
+        );
+
+        // A record made through another manifest of the same device is kept.
+        let mut other_manifest = DeployManifest {
+            path: dir.join("deployed.json"),
+            ..DeployManifest::default()
+        };
+        other_manifest.record("/tmp/other_test", "00").unwrap();
+        manifest.forget("/tmp/fargo_test").unwrap();
+        assert_eq!(manifest.deployed_hash("/tmp/other_test"), Some("00"));
+        assert_eq!(manifest.deployed_hash("/tmp/fargo_test"), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -2,48 +2,286 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use atty::{self, Stream};
+use error::FargoError;
 use failure::{Error, ResultExt, err_msg};
-use sdk::{TargetOptions, fuchsia_root, target_out_dir};
-use std::{str, thread, time};
+use netboot::{Device, discover_devices, find_device};
+#[cfg(feature = "native-ssh")]
+use native_ssh::NativeSshTransport;
+use sdk::{TargetOptions, fuchsia_root, out_dir_name, target_out_dir};
+use serde_json;
+use std::{io, str, thread, time};
 use std::env;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
-use utils::is_mac;
+use std::process::{self, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{RecvTimeoutError, channel};
+use std::time::Instant;
+use utils::{format_table, is_mac};
 
 pub fn netaddr(verbose: bool, target_options: &TargetOptions) -> Result<String, Error> {
-    let fuchsia_root = fuchsia_root(target_options)?;
-    let netaddr_binary = fuchsia_root.join("out/build-zircon/tools/netaddr");
-    let mut args = vec!["--fuchsia"];
-    if let Some(device_name) = target_options.device_name {
-        args.push(device_name);
+    if let Some(device_address) = target_options.device_address {
+        let (host, _) = parse_device_address(device_address)?;
+        return Ok(host.to_string());
     }
-    let netaddr_result = Command::new(netaddr_binary).args(args).output()?;
-    let result = str::from_utf8(&netaddr_result.stdout).unwrap().trim().to_string();
-    if verbose {
-        println!("netaddr status = {}, result = {}", netaddr_result.status, result);
+    if let Some(netaddr) = control_master_netaddr(target_options)? {
+        if verbose {
+            println!("netaddr from control master = {}", netaddr);
+        }
+        return Ok(netaddr);
     }
-    if !netaddr_result.status.success() {
-        let err_str = str::from_utf8(&netaddr_result.stderr).unwrap().trim().to_string();
-        bail!("netaddr failed with status {:?}: {}", netaddr_result.status, err_str);
+    let device = match target_options.device_name {
+        Some(device_name) => find_device(verbose, device_name)?,
+        None => {
+            let mut devices = discover_devices(verbose)?;
+            if devices.len() > 1 {
+                return Err(FargoError::MultipleDevices(
+                    devices.into_iter().map(|device| device.nodename).collect(),
+                ).into());
+            }
+            devices.pop()
+        }
+    };
+    match device {
+        Some(device) => {
+            if verbose {
+                println!("netaddr of {} = {}", device.nodename, device.netaddr());
+            }
+            Ok(device.netaddr())
+        }
+        None => {
+            let device_name = target_options.device_name.map(String::from);
+            Err(FargoError::DeviceNotFound(device_name).into())
+        }
     }
-    Ok(result)
 }
 
-pub fn netls(verbose: bool, target_options: &TargetOptions) -> Result<(), Error> {
-    let fuchsia_root = fuchsia_root(target_options)?;
-    let netls_binary = fuchsia_root.join("out/build-zircon/tools/netls");
-    let mut netls_command = Command::new(netls_binary);
-    netls_command.arg("--nowait").arg("--timeout=500");
-    if verbose {
-        println!("{:?}", netls_command);
+/// Picks the device to use when none was named, asking which one to use if more than
+/// one is visible on the network and fargo runs in a terminal. Returns the nodename of
+/// the chosen device, or `None` if no device is visible.
+pub fn select_device(verbose: bool) -> Result<Option<String>, Error> {
+    let devices = discover_devices(verbose)?;
+    if devices.len() < 2 {
+        return Ok(devices.into_iter().next().map(|device| device.nodename));
+    }
+    let nodenames: Vec<String> = devices.iter().map(|device| device.nodename.clone()).collect();
+    // The prompt goes to stderr to keep stdout for the output of the command.
+    if !atty::is(Stream::Stdin) || !atty::is(Stream::Stderr) {
+        return Err(FargoError::MultipleDevices(nodenames).into());
+    }
+
+    eprintln!("multiple Fuchsia devices found:");
+    eprintln!("{}", format_candidates(&devices));
+    eprint!("device to use [1-{}]: ", devices.len());
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    match choose_device(&nodenames, &answer) {
+        Some(index) => Ok(Some(nodenames[index].clone())),
+        None => Err(FargoError::MultipleDevices(nodenames).into()),
+    }
+}
+
+fn format_candidates(devices: &[Device]) -> String {
+    let mut rows = vec![vec![String::from("#"), String::from("NODENAME"), String::from("ADDRESS")]];
+    for (index, device) in devices.iter().enumerate() {
+        rows.push(vec![(index + 1).to_string(), device.nodename.clone(), device.netaddr()]);
+    }
+    format_table(&rows)
+}
+
+/// Returns the index of the device `answer` picks from `nodenames`, either by its
+/// number in the list of candidates or by its nodename.
+fn choose_device(nodenames: &[String], answer: &str) -> Option<usize> {
+    let answer = answer.trim();
+    match answer.parse::<usize>() {
+        Ok(number) if (1..=nodenames.len()).contains(&number) => Some(number - 1),
+        _ => nodenames.iter().position(|nodename| nodename == answer),
+    }
+}
+
+/// Splits a device address given as `host`, `host:port` or `[host]:port` into its host
+/// and port. An IPv6 address needs the brackets to have a port.
+pub fn parse_device_address(address: &str) -> Result<(&str, Option<u16>), Error> {
+    let parse_port = |port: &str| {
+        port.parse::<u16>()
+            .map_err(|_| format_err!("invalid port '{}' in device address '{}'", port, address))
+    };
+    if address.starts_with('[') {
+        let end = address
+            .find(']')
+            .ok_or_else(|| format_err!("missing ']' in device address '{}'", address))?;
+        let host = &address[1..end];
+        return match &address[end + 1..] {
+            "" => Ok((host, None)),
+            rest if rest.starts_with(':') => Ok((host, Some(parse_port(&rest[1..])?))),
+            _ => bail!("unexpected text after ']' in device address '{}'", address),
+        };
     }
-    let netls_status = netls_command.status()?;
-    if !netls_status.success() {
-        bail!("netlst failed with error {:?}", netls_status);
+    let mut parts = address.splitn(2, ':');
+    let host = parts.next().unwrap_or("");
+    match parts.next() {
+        Some(port) if !port.contains(':') => Ok((host, Some(parse_port(port)?))),
+        _ => Ok((address, None)),
     }
+}
+
+/// Returns the port of the device address given with `--device-addr`, if it has one.
+fn device_port(target_options: &TargetOptions) -> Result<Option<u16>, Error> {
+    match target_options.device_address {
+        Some(device_address) => Ok(parse_device_address(device_address)?.1),
+        None => Ok(None),
+    }
+}
+
+/// The option that makes ssh, with `-p`, or scp, with `-P`, use the port of the
+/// device address.
+fn port_args(target_options: &TargetOptions, port_flag: &str) -> Result<Vec<String>, Error> {
+    Ok(match device_port(target_options)? {
+        Some(port) => vec![port_flag.to_string(), port.to_string()],
+        None => vec![],
+    })
+}
+
+/// The port ssh connects to: the one of the device address, else `config_port` from
+/// the ssh config, else the standard one.
+pub fn ssh_port(target_options: &TargetOptions, config_port: Option<u16>) -> Result<u16, Error> {
+    Ok(device_port(target_options)?.or(config_port).unwrap_or(22))
+}
+
+/// What `fargo list-devices` reports about a device found on the network.
+#[derive(Debug, Serialize)]
+pub struct DeviceStatus {
+    pub nodename: String,
+    pub address: String,
+    pub interface: String,
+    /// Whether the device accepts connections on the ssh port.
+    pub ssh_reachable: bool,
+    /// A guess at the Fuchsia build the device runs, i.e. `release-x64`: the first
+    /// build whose ssh key the device accepts. Builds that share a key can't be told
+    /// apart.
+    pub variant: Option<String>,
+}
+
+/// How long probing a device for `list-devices` waits for each connection.
+const PROBE_TIMEOUT_MS: u64 = 1000;
+
+/// Guesses the Fuchsia build the device at `netaddr` runs from which of the release
+/// and debug builds for the target CPU it lets authenticate with ssh first.
+fn probe_variant(verbose: bool, target_options: &TargetOptions, netaddr: &str) -> Option<String> {
+    for &release_os in &[true, false] {
+        let mut variant_options = target_options.clone();
+        variant_options.release_os = release_os;
+        match probe_ssh(&variant_options, netaddr) {
+            Ok(()) => return Some(out_dir_name(&variant_options)),
+            Err(error) => if verbose {
+                println!("{} is not {}: {}", netaddr, out_dir_name(&variant_options), error);
+            },
+        }
+    }
+    None
+}
+
+/// Checks that ssh can log in to the device at `netaddr` with the identity from the
+/// ssh config of the Fuchsia build selected by `target_options`, without prompting.
+fn probe_ssh(target_options: &TargetOptions, netaddr: &str) -> Result<(), Error> {
+    let connect_timeout = format!("ConnectTimeout={}", PROBE_TIMEOUT_MS / 1000);
+    let status = Command::new("ssh")
+        .env_remove("SSH_AUTH_SOCK")
+        .arg("-q")
+        .arg("-F")
+        .arg(ssh_config_path(target_options)?)
+        // ssh uses the first value it is given for an option, so these go first.
+        .args(["-o", "BatchMode=yes", "-o", &connect_timeout])
+        .args(SSH_OPTIONS)
+        .args(port_args(target_options, "-p")?)
+        .arg(netaddr)
+        .arg("exit")
+        .stdin(Stdio::null())
+        .status()
+        .context("unable to run ssh")?;
+    remote_exit_code(status)?;
     Ok(())
 }
 
+/// Finds the devices on the network and checks whether ssh can reach them.
+pub fn probe_devices(
+    verbose: bool,
+    target_options: &TargetOptions,
+) -> Result<Vec<DeviceStatus>, Error> {
+    // Without an ssh config the port can still come from the device address.
+    let config_port = SshConfig::load(target_options).ok().and_then(|ssh_config| ssh_config.port);
+    let port = ssh_port(target_options, config_port)?;
+    let mut statuses = vec![];
+    for device in discover_devices(verbose)? {
+        let netaddr = device.netaddr();
+        let ssh_reachable = device_socket_address(&netaddr, port)
+            .and_then(|address| {
+                let timeout = time::Duration::from_millis(PROBE_TIMEOUT_MS);
+                Ok(TcpStream::connect_timeout(&address, timeout)?)
+            })
+            .is_ok();
+        let variant = if ssh_reachable {
+            probe_variant(verbose, target_options, &netaddr)
+        } else {
+            None
+        };
+        statuses.push(DeviceStatus {
+            nodename: device.nodename,
+            address: device.address.to_string(),
+            interface: device.interface,
+            ssh_reachable,
+            variant,
+        });
+    }
+    Ok(statuses)
+}
+
+/// Formats devices as a table with a header line, one device per line.
+pub fn format_device_table(statuses: &[DeviceStatus]) -> String {
+    let mut rows = vec![
+        vec![
+            String::from("NODENAME"),
+            String::from("ADDRESS"),
+            String::from("INTERFACE"),
+            String::from("SSH"),
+            String::from("VARIANT"),
+        ],
+    ];
+    for status in statuses {
+        rows.push(vec![
+            status.nodename.clone(),
+            status.address.clone(),
+            status.interface.clone(),
+            String::from(if status.ssh_reachable { "yes" } else { "no" }),
+            status.variant.clone().unwrap_or_else(|| String::from("-")),
+        ]);
+    }
+    format_table(&rows)
+}
+
+/// Prints the devices found on the network, as a table or as a JSON array.
+pub fn list_devices(
+    verbose: bool,
+    target_options: &TargetOptions,
+    json: bool,
+) -> Result<(), Error> {
+    let statuses = probe_devices(verbose, target_options)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&statuses)?);
+    } else {
+        print!("{}", format_device_table(&statuses));
+    }
+    Ok(())
+}
+
+/// Returns the names of the devices found on the network.
+pub fn discovered_device_names(verbose: bool) -> Result<Vec<String>, Error> {
+    Ok(discover_devices(verbose)?.into_iter().map(|device| device.nodename).collect())
+}
+
 static SSH_OPTIONS: &'static [&str] = &[
     "-o",
     "UserKnownHostsFile=/dev/null",
@@ -51,8 +289,170 @@ static SSH_OPTIONS: &'static [&str] = &[
     "StrictHostKeyChecking=no",
     "-o",
     "ConnectTimeout=20",
+    "-o",
+    "ServerAliveInterval=10",
+    "-o",
+    "ServerAliveCountMax=3",
 ];
 
+fn ssh_config_path(target_options: &TargetOptions) -> Result<PathBuf, Error> {
+    let ssh_config = match target_options.ssh_config {
+        Some(ssh_config) => ssh_config.to_path_buf(),
+        None => target_out_dir(target_options)?.join("ssh-keys/ssh_config"),
+    };
+    if !ssh_config.exists() {
+        return Err(FargoError::SshConfigMissing(ssh_config).into());
+    }
+    Ok(ssh_config)
+}
+
+/// Where the ssh control master of a fargo run keeps its socket and how it is started.
+pub struct ControlMasterOptions {
+    /// The directory of the socket, which every fargo process of the run, including
+    /// the `run-on-target` runners started by cargo, must be able to reach.
+    pub control_dir: PathBuf,
+    pub ssh_program: PathBuf,
+    /// Tells apart the masters of concurrent runs for the same device.
+    pub run_id: String,
+}
+
+impl ControlMasterOptions {
+    /// Options that keep the socket under `~/.fargo` and identify the run by the id of
+    /// the fargo process.
+    pub fn new() -> Result<ControlMasterOptions, Error> {
+        let home_value = env::var("HOME")?;
+        Ok(ControlMasterOptions {
+            control_dir: PathBuf::from(home_value).join(".fargo"),
+            ssh_program: PathBuf::from("ssh"),
+            run_id: process::id().to_string(),
+        })
+    }
+
+    /// Path of the socket of the ssh control master for the target device.
+    pub fn control_path(&self, target_options: &TargetOptions) -> PathBuf {
+        let control_name = format!("ssh-control-{}-{}", target_options.device_key(), self.run_id);
+        self.control_dir.join(control_name)
+    }
+}
+
+fn control_netaddr_path(control_path: &Path) -> PathBuf {
+    control_path.with_extension("netaddr")
+}
+
+/// Options that make ssh and scp reuse the control master connection if one is running.
+/// If the master has gone away ssh falls back to making its own connection.
+fn control_options(target_options: &TargetOptions) -> Vec<String> {
+    match target_options.control_path {
+        Some(control_path) if control_path.exists() => vec![
+            String::from("-o"),
+            format!("ControlPath={}", control_path.to_string_lossy()),
+            String::from("-o"),
+            String::from("ControlMaster=no"),
+        ],
+        _ => vec![],
+    }
+}
+
+/// Returns the device address recorded by the control master of the run, avoiding a
+/// network lookup by `netaddr`.
+fn control_master_netaddr(target_options: &TargetOptions) -> Result<Option<String>, Error> {
+    let netaddr_path = match target_options.control_path {
+        Some(control_path) => control_netaddr_path(control_path),
+        None => return Ok(None),
+    };
+    if !netaddr_path.exists() {
+        return Ok(None);
+    }
+    let mut netaddr = String::new();
+    File::open(&netaddr_path)?.read_to_string(&mut netaddr)?;
+    Ok(Some(netaddr.trim().to_string()))
+}
+
+/// A persistent, multiplexed ssh connection to the target device. Until it is dropped,
+/// `ssh` and `scp_to_device` run over this connection instead of each doing their own
+/// TCP connection and authentication, if their `TargetOptions` have its control path.
+pub struct ControlMaster {
+    verbose: bool,
+    ssh_program: PathBuf,
+    ssh_config: PathBuf,
+    control_path: PathBuf,
+}
+
+impl ControlMaster {
+    pub fn control_path(&self) -> &Path {
+        &self.control_path
+    }
+}
+
+pub fn start_control_master(
+    verbose: bool,
+    target_options: &TargetOptions,
+    options: &ControlMasterOptions,
+) -> Result<ControlMaster, Error> {
+    let netaddr = netaddr(verbose, target_options)?;
+    let ssh_config = ssh_config_path(target_options)?;
+    let control_path = options.control_path(target_options);
+    fs::create_dir_all(&options.control_dir)?;
+    let mut master_command = Command::new(&options.ssh_program);
+    master_command
+        .env_remove("SSH_AUTH_SOCK")
+        .arg("-q")
+        .arg("-F")
+        .arg(&ssh_config)
+        .args(SSH_OPTIONS)
+        .args(port_args(target_options, "-p")?)
+        .arg("-o")
+        .arg("ControlMaster=yes")
+        .arg("-o")
+        .arg(format!("ControlPath={}", control_path.to_string_lossy()))
+        .arg("-o")
+        .arg("ControlPersist=yes")
+        .arg("-N")
+        .arg("-f")
+        .arg(&netaddr);
+
+    if verbose {
+        println!("{:?}", master_command);
+    }
+
+    let master_status = master_command.status().context("unable to run ssh")?;
+    if !master_status.success() {
+        return Err(FargoError::TransportFailed(
+            format!("starting ssh control master failed: {}", master_status),
+        ).into());
+    }
+    File::create(control_netaddr_path(&control_path))?.write_all(netaddr.as_bytes())?;
+
+    Ok(ControlMaster {
+        verbose,
+        ssh_program: options.ssh_program.clone(),
+        ssh_config,
+        control_path,
+    })
+}
+
+impl Drop for ControlMaster {
+    fn drop(&mut self) {
+        if self.verbose {
+            println!("stopping ssh control master at {:?}", self.control_path);
+        }
+        Command::new(&self.ssh_program)
+            .env_remove("SSH_AUTH_SOCK")
+            .arg("-q")
+            .arg("-F")
+            .arg(&self.ssh_config)
+            .arg("-o")
+            .arg(format!("ControlPath={}", self.control_path.to_string_lossy()))
+            .arg("-O")
+            .arg("exit")
+            .arg("fuchsia")
+            .stderr(Stdio::null())
+            .status()
+            .ok();
+        fs::remove_file(control_netaddr_path(&self.control_path)).ok();
+    }
+}
+
 pub fn scp_to_device(
     verbose: bool,
     target_options: &TargetOptions,
@@ -61,10 +461,7 @@ pub fn scp_to_device(
     destination_path: &str,
 ) -> Result<(), Error> {
     let destination_with_address = format!("[{}]:{}", netaddr, destination_path);
-    let ssh_config = target_out_dir(target_options)?.join("ssh-keys/ssh_config");
-    if !ssh_config.exists() {
-        bail!("ssh config not found at {:?}", ssh_config);
-    }
+    let ssh_config = ssh_config_path(target_options)?;
     if verbose {
         println!("destination_with_address = {}", destination_with_address);
         println!("ssh_config = {:?}", ssh_config);
@@ -78,6 +475,8 @@ pub fn scp_to_device(
         .arg("-F")
         .arg(ssh_config)
         .args(SSH_OPTIONS)
+        .args(port_args(target_options, "-P")?)
+        .args(control_options(target_options))
         .arg(source_path)
         .arg(destination_with_address);
 
@@ -88,34 +487,242 @@ pub fn scp_to_device(
     let scp_result = scp_command.status().context("unable to run scp")?;
 
     if !scp_result.success() {
-        bail!("scp failed with error {:?}", scp_result);
+        return Err(FargoError::TransportFailed(format!("scp failed: {}", scp_result)).into());
     }
 
     Ok(())
 }
 
-pub fn ssh(verbose: bool, target_options: &TargetOptions, command: &str) -> Result<(), Error> {
+/// Builds, but does not start, the ssh command that runs `command` on the target.
+pub fn ssh_command(
+    verbose: bool,
+    target_options: &TargetOptions,
+    command: &str,
+) -> Result<Command, Error> {
     let netaddr = netaddr(verbose, target_options)?;
-    let ssh_config = target_out_dir(target_options)?.join("ssh-keys/ssh_config");
-    if !ssh_config.exists() {
-        bail!("ssh config not found at {:?}", ssh_config);
-    }
-    let ssh_result = Command::new("ssh")
+    ssh_command_to_address(target_options, &netaddr, command)
+}
+
+fn ssh_command_to_address(
+    target_options: &TargetOptions,
+    netaddr: &str,
+    command: &str,
+) -> Result<Command, Error> {
+    let mut ssh_command = Command::new("ssh");
+    ssh_command
         .env_remove("SSH_AUTH_SOCK")
         .arg("-q")
         .arg("-F")
-        .arg(ssh_config)
+        .arg(ssh_config_path(target_options)?)
         .args(SSH_OPTIONS)
+        .args(port_args(target_options, "-p")?)
+        .args(control_options(target_options))
         .arg(netaddr)
-        .arg(command)
-        .status()
-        .context("unable to run ssh")?;
+        .arg(command);
+    Ok(ssh_command)
+}
+
+/// The exit status ssh uses for its own failures, such as being unable to connect or
+/// authenticate, as opposed to an exit status of the remote command.
+const SSH_FAILED_EXIT_CODE: i32 = 255;
 
-    if !ssh_result.success() {
-        bail!("ssh failed: {}", ssh_result);
+fn remote_exit_code(ssh_status: ExitStatus) -> Result<i32, Error> {
+    match ssh_status.code() {
+        Some(SSH_FAILED_EXIT_CODE) | None => {
+            Err(FargoError::TransportFailed(format!("ssh failed: {}", ssh_status)).into())
+        }
+        Some(exit_code) => Ok(exit_code),
     }
+}
 
-    Ok(())
+/// Runs `command` on the target, or an interactive shell if `command` is empty, and
+/// returns the exit code of the remote command.
+pub fn ssh(verbose: bool, target_options: &TargetOptions, command: &str) -> Result<i32, Error> {
+    let ssh_result = ssh_command(verbose, target_options, command)?.status().context(
+        "unable to run ssh",
+    )?;
+    remote_exit_code(ssh_result)
+}
+
+/// A connection to a Fuchsia device that can copy files to it and run commands on it.
+/// Failures of the connection itself are reported as errors, never as exit codes.
+pub trait Transport {
+    fn copy_to_device(&mut self, source_path: &Path, destination_path: &str) -> Result<(), Error>;
+
+    /// Runs `command` on the device with its output going to fargo's stdout and stderr,
+    /// returning the exit code of the command.
+    fn run(&mut self, command: &str) -> Result<i32, Error>;
+
+    /// Runs `command` on the device, calling `on_line` with each line of its standard
+    /// output, and returns the exit code of the command.
+    fn run_with_output(
+        &mut self,
+        command: &str,
+        on_line: &mut dyn FnMut(&str) -> Result<(), Error>,
+    ) -> Result<i32, Error>;
+
+    /// Like `run_with_output`, but stops waiting for `command` once `timeout` has passed
+    /// and returns `None`. The command may then still be running on the device.
+    fn run_with_timeout(
+        &mut self,
+        command: &str,
+        on_line: &mut dyn FnMut(&str) -> Result<(), Error>,
+        timeout: time::Duration,
+    ) -> Result<Option<i32>, Error>;
+}
+
+/// Connects to the target device using the transport selected in `target_options`.
+pub fn connect<'a>(
+    verbose: bool,
+    target_options: &'a TargetOptions,
+) -> Result<Box<dyn Transport + 'a>, Error> {
+    if target_options.native_ssh {
+        #[cfg(feature = "native-ssh")]
+        return Ok(Box::new(NativeSshTransport::connect(verbose, target_options)?));
+        #[cfg(not(feature = "native-ssh"))]
+        bail!("--native-ssh needs a fargo built with the native-ssh feature");
+    }
+    Ok(Box::new(OpenSshTransport::new(verbose, target_options)?))
+}
+
+/// Transport that runs the system `ssh` and `scp` binaries for every operation.
+pub struct OpenSshTransport<'a> {
+    verbose: bool,
+    target_options: &'a TargetOptions<'a>,
+    netaddr: String,
+}
+
+impl<'a> OpenSshTransport<'a> {
+    pub fn new(
+        verbose: bool,
+        target_options: &'a TargetOptions,
+    ) -> Result<OpenSshTransport<'a>, Error> {
+        Ok(OpenSshTransport {
+            verbose,
+            target_options,
+            netaddr: netaddr(verbose, target_options)?,
+        })
+    }
+}
+
+impl<'a> Transport for OpenSshTransport<'a> {
+    fn copy_to_device(&mut self, source_path: &Path, destination_path: &str) -> Result<(), Error> {
+        scp_to_device(
+            self.verbose,
+            self.target_options,
+            &self.netaddr,
+            &source_path.to_path_buf(),
+            destination_path,
+        )
+    }
+
+    fn run(&mut self, command: &str) -> Result<i32, Error> {
+        let status = ssh_command_to_address(self.target_options, &self.netaddr, command)?
+            .status()
+            .context("unable to run ssh")?;
+        remote_exit_code(status)
+    }
+
+    fn run_with_output(
+        &mut self,
+        command: &str,
+        on_line: &mut dyn FnMut(&str) -> Result<(), Error>,
+    ) -> Result<i32, Error> {
+        let mut child = ssh_command_to_address(self.target_options, &self.netaddr, command)?
+            .stdout(Stdio::piped())
+            .spawn()
+            .context("unable to run ssh")?;
+        {
+            let stdout = child.stdout.take().unwrap();
+            for line in BufReader::new(stdout).lines() {
+                on_line(&line?)?;
+            }
+        }
+        let status = child.wait().context("unable to wait for ssh")?;
+        remote_exit_code(status)
+    }
+
+    fn run_with_timeout(
+        &mut self,
+        command: &str,
+        on_line: &mut dyn FnMut(&str) -> Result<(), Error>,
+        timeout: time::Duration,
+    ) -> Result<Option<i32>, Error> {
+        let deadline = Instant::now() + timeout;
+        let mut child = ssh_command_to_address(self.target_options, &self.netaddr, command)?
+            .stdout(Stdio::piped())
+            .spawn()
+            .context("unable to run ssh")?;
+
+        // Lines are read on another thread so that waiting for them can time out.
+        let stdout = child.stdout.take().unwrap();
+        let (sender, receiver) = channel();
+        thread::spawn(move || for line in BufReader::new(stdout).lines() {
+            if sender.send(line).is_err() {
+                break;
+            }
+        });
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match receiver.recv_timeout(remaining) {
+                Ok(line) => on_line(&line?)?,
+                Err(RecvTimeoutError::Disconnected) => break,
+                Err(RecvTimeoutError::Timeout) => {
+                    child.kill().ok();
+                    child.wait().ok();
+                    return Ok(None);
+                }
+            }
+        }
+        let status = child.wait().context("unable to wait for ssh")?;
+        Ok(Some(remote_exit_code(status)?))
+    }
+}
+
+/// The subset of the settings in the Fuchsia build's `ssh-keys/ssh_config` that the
+/// native transport understands.
+#[derive(Debug, Default, PartialEq)]
+pub struct SshConfig {
+    pub user: Option<String>,
+    pub identity_file: Option<PathBuf>,
+    pub port: Option<u16>,
+}
+
+impl SshConfig {
+    pub fn parse(contents: &str) -> SshConfig {
+        let mut config = SshConfig::default();
+        for line in contents.lines() {
+            let mut parts = line.trim().splitn(2, char::is_whitespace);
+            let key = parts.next().unwrap_or("").to_lowercase();
+            let value = parts.next().unwrap_or("").trim().trim_matches('"');
+            match key.as_str() {
+                "user" => config.user = Some(value.to_string()),
+                "identityfile" => config.identity_file = Some(PathBuf::from(value)),
+                "port" => config.port = value.parse().ok(),
+                _ => (),
+            }
+        }
+        config
+    }
+
+    pub fn load(target_options: &TargetOptions) -> Result<SshConfig, Error> {
+        let ssh_config_path = ssh_config_path(target_options)?;
+        let mut contents = String::new();
+        File::open(&ssh_config_path)?.read_to_string(&mut contents)?;
+        Ok(SshConfig::parse(&contents))
+    }
+}
+
+pub fn transport_failed(reason: String) -> FargoError {
+    FargoError::TransportFailed(reason)
+}
+
+pub fn device_socket_address(netaddr: &str, port: u16) -> Result<SocketAddr, Error> {
+    Ok((netaddr, port)
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addresses| addresses.next())
+        .ok_or_else(|| transport_failed(format!("unable to resolve device address {}", netaddr)))?)
 }
 
 pub fn setup_network_mac(user: &str) -> Result<(), Error> {
@@ -238,11 +845,165 @@ pub fn start_emulator(
     if with_networking { setup_network() } else { Ok(()) }
 }
 
-pub fn stop_emulator() -> Result<(), Error> {
-    Command::new("killall").arg("qemu-system-x86_64").status()?;
+pub fn stop_emulator(target_options: &TargetOptions) -> Result<(), Error> {
+    let qemu_name = format!("qemu-system-{}", target_options.target_cpu_linker);
+    Command::new("killall").arg(qemu_name).status()?;
     Ok(())
 }
 
 pub fn enable_networking() -> Result<(), Error> {
     setup_network()
 }
+
+#[cfg(test)]
+mod tests {
+    use device::{ControlMasterOptions, DeviceStatus, SshConfig, choose_device,
+                 control_master_netaddr, control_options, format_device_table,
+                 parse_device_address, start_control_master};
+    use sdk::TargetOptions;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+    use std::process;
+
+    #[test]
+    fn test_parse_ssh_config() {
+        let ssh_config = SshConfig::parse(
+            "Host *
+  CheckHostIP no
+  StrictHostKeyChecking no
+  User fuchsia
+  IdentitiesOnly yes
+  IdentityFile \"/fuchsia/out/release-x64/ssh-keys/id_ed25519\"
+  ConnectTimeout 10
+",
+        );
+        assert_eq!(ssh_config.user, Some(String::from("fuchsia")));
+        assert_eq!(
+            ssh_config.identity_file,
+            Some(PathBuf::from("/fuchsia/out/release-x64/ssh-keys/id_ed25519"))
+        );
+        assert_eq!(ssh_config.port, None);
+    }
+
+    #[test]
+    fn test_choose_device() {
+        let nodenames =
+            vec![String::from("ivy-donut-grew-stoop"), String::from("shun-gully-wrath-bunny")];
+        assert_eq!(choose_device(&nodenames, "2\n"), Some(1));
+        assert_eq!(choose_device(&nodenames, "ivy-donut-grew-stoop\n"), Some(0));
+        assert_eq!(choose_device(&nodenames, "0\n"), None);
+        assert_eq!(choose_device(&nodenames, "3\n"), None);
+        assert_eq!(choose_device(&nodenames, "\n"), None);
+    }
+
+    #[test]
+    fn test_parse_device_address() {
+        assert_eq!(parse_device_address("192.168.42.2").unwrap(), ("192.168.42.2", None));
+        assert_eq!(parse_device_address("localhost:8022").unwrap(), ("localhost", Some(8022)));
+        assert_eq!(parse_device_address("fe80::1%eth0").unwrap(), ("fe80::1%eth0", None));
+        assert_eq!(parse_device_address("[fe80::1%eth0]").unwrap(), ("fe80::1%eth0", None));
+        assert_eq!(parse_device_address("[::1]:8022").unwrap(), ("::1", Some(8022)));
+        assert!(parse_device_address("localhost:ssh").is_err());
+        assert!(parse_device_address("[::1]8022").is_err());
+        assert!(parse_device_address("[::1:8022").is_err());
+    }
+
+    #[test]
+    fn test_format_device_table() {
+        let statuses = vec![
+            DeviceStatus {
+                nodename: String::from("ivy-donut-grew-stoop"),
+                address: String::from("fe80::5054:ff:fe63:5e7a"),
+                interface: String::from("qemu"),
+                ssh_reachable: true,
+                variant: Some(String::from("release-x64")),
+            },
+            DeviceStatus {
+                nodename: String::from("shun-gully-wrath-bunny"),
+                address: String::from("fe80::8eae:4cff:fef4:9247"),
+                interface: String::from("enp0s20f0u2"),
+                ssh_reachable: false,
+                variant: None,
+            },
+        ];
+        assert_eq!(
+            format_device_table(&statuses),
+            "NODENAME                ADDRESS                    INTERFACE    SSH  VARIANT\n\
+             ivy-donut-grew-stoop    fe80::5054:ff:fe63:5e7a    qemu         yes  release-x64\n\
+             shun-gully-wrath-bunny  fe80::8eae:4cff:fef4:9247  enp0s20f0u2  no   -\n"
+        );
+    }
+
+    /// Stands in for ssh, keeping a file at the control path while the master runs.
+    const FAKE_SSH: &str = r#"#!/bin/sh
+control_path=""
+operation=""
+previous=""
+for arg in "$@"; do
+    case "$arg" in
+        ControlPath=*) control_path="${arg#ControlPath=}" ;;
+    esac
+    if [ "$previous" = "-O" ]; then
+        operation="$arg"
+    fi
+    previous="$arg"
+done
+case "$operation" in
+    "") touch "$control_path" ;;
+    exit) rm -f "$control_path" ;;
+esac
+"#;
+
+    #[test]
+    fn test_control_master() {
+        let test_dir = env::temp_dir().join(format!("fargo-control-master-{}", process::id()));
+        fs::create_dir_all(&test_dir).unwrap();
+        let fake_ssh_path = test_dir.join("ssh");
+        File::create(&fake_ssh_path).unwrap().write_all(FAKE_SSH.as_bytes()).unwrap();
+        fs::set_permissions(&fake_ssh_path, fs::Permissions::from_mode(0o755)).unwrap();
+        let ssh_config_path = test_dir.join("ssh_config");
+        File::create(&ssh_config_path).unwrap();
+
+        let mut target_options = TargetOptions::new(true, "x64", None);
+        target_options.device_address = Some("192.168.42.2");
+        target_options.ssh_config = Some(&ssh_config_path);
+        let options = ControlMasterOptions {
+            control_dir: test_dir.join("control"),
+            ssh_program: fake_ssh_path.clone(),
+            run_id: String::from("42"),
+        };
+        let control_path = options.control_path(&target_options);
+        assert!(control_path.starts_with(&options.control_dir));
+        assert!(control_path.to_string_lossy().ends_with("-42"));
+
+        let control_master = start_control_master(false, &target_options, &options).unwrap();
+        assert_eq!(control_master.control_path(), control_path);
+        assert!(control_path.exists());
+        assert_eq!(control_options(&target_options), Vec::<String>::new());
+        assert_eq!(control_master_netaddr(&target_options).unwrap(), None);
+
+        // The runners of the run get the control path with their target options.
+        let mut runner_options = target_options.clone();
+        runner_options.control_path = Some(control_master.control_path());
+        assert_eq!(
+            control_options(&runner_options)[1],
+            format!("ControlPath={}", control_path.to_string_lossy())
+        );
+        assert_eq!(
+            control_master_netaddr(&runner_options).unwrap(),
+            Some(String::from("192.168.42.2"))
+        );
+
+        drop(control_master);
+        assert!(!control_path.exists());
+        let mut runner_options = target_options.clone();
+        runner_options.control_path = Some(&control_path);
+        assert_eq!(control_options(&runner_options), Vec::<String>::new());
+        assert_eq!(control_master_netaddr(&runner_options).unwrap(), None);
+
+        fs::remove_dir_all(&test_dir).unwrap();
+    }
+}
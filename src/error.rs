@@ -0,0 +1,129 @@
+// Copyright 2018 The Fuchsia Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use failure::{Error, Fail};
+use std::fmt;
+use std::path::PathBuf;
+
+/// Errors that programs using fargo as a library might want to handle. Fargo
+/// functions return them inside a `failure::Error`, so use `downcast_ref` to
+/// find out which one occurred.
+///
+/// # Examples
+///
+/// ```no_run
+/// use fargo::{run_cargo, FargoError, TargetOptions};
+///
+/// let target_options = TargetOptions::new(true, "x64", None);
+/// if let Err(error) = run_cargo(false, true, false, &["build"], &target_options, None, None) {
+///     match error.downcast_ref::<FargoError>() {
+///         Some(&FargoError::CargoFailed(exit_code)) => println!("cargo failed: {}", exit_code),
+///         _ => println!("error: {}", error),
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub enum FargoError {
+    /// FUCHSIA_ROOT is not set and no Fuchsia tree with a build in the named out
+    /// directory, i.e. `release-x64`, contains the current directory.
+    FuchsiaRootNotFound(String),
+    /// The Fuchsia build output directory does not exist.
+    OutDirMissing(PathBuf),
+    /// The ssh configuration generated by the Fuchsia build does not exist.
+    SshConfigMissing(PathBuf),
+    /// No device, or no device with the given name, could be found.
+    DeviceNotFound(Option<String>),
+    /// More than one device is visible and none was selected.
+    MultipleDevices(Vec<String>),
+    /// The device could not be reached, or copying to it failed.
+    TransportFailed(String),
+    /// A command run on the device exited with a non-zero exit code.
+    RemoteExit(i32),
+    /// Cargo exited with a non-zero exit code.
+    CargoFailed(i32),
+}
+
+impl fmt::Display for FargoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FargoError::FuchsiaRootNotFound(ref out_dir_name) => write!(
+                f,
+                "FUCHSIA_ROOT not set and current directory is not in a Fuchsia tree with a \
+                 {} build. You must set the environmental variable FUCHSIA_ROOT to point to a \
+                 Fuchsia tree with a {} build.",
+                out_dir_name,
+                out_dir_name
+            ),
+            FargoError::OutDirMissing(ref path) => {
+                write!(f, "no target out directory found at {:?}", path)
+            }
+            FargoError::SshConfigMissing(ref path) => {
+                write!(f, "ssh config not found at {:?}", path)
+            }
+            FargoError::DeviceNotFound(Some(ref device_name)) => {
+                write!(f, "Fuchsia device '{}' not found", device_name)
+            }
+            FargoError::DeviceNotFound(None) => write!(f, "no Fuchsia device found"),
+            FargoError::MultipleDevices(ref device_names) => write!(
+                f,
+                "multiple Fuchsia devices found, use --device-name or a default device to pick \
+                 one of: {}",
+                device_names.join(", ")
+            ),
+            FargoError::TransportFailed(ref reason) => {
+                write!(f, "unable to communicate with device: {}", reason)
+            }
+            FargoError::RemoteExit(exit_code) => {
+                write!(f, "command on device exited with status {}", exit_code)
+            }
+            FargoError::CargoFailed(exit_code) => {
+                write!(f, "cargo exited with status {}", exit_code)
+            }
+        }
+    }
+}
+
+impl Fail for FargoError {}
+
+/// Exit code used when the device could not be reached, matching the one used by ssh, so
+/// that callers can tell it apart from failures of the program run on the device.
+pub const TRANSPORT_FAILED_EXIT_CODE: i32 = 255;
+
+/// Returns whether `error` was caused by a failure to reach or talk to the device.
+pub fn is_transport_failure(error: &Error) -> bool {
+    error.iter_chain().any(|cause| {
+        matches!(cause.downcast_ref::<FargoError>(), Some(&FargoError::TransportFailed(_)))
+    })
+}
+
+/// The exit code for fargo to exit with after failing with `error`.
+pub fn exit_code(error: &Error) -> i32 {
+    if is_transport_failure(error) {
+        TRANSPORT_FAILED_EXIT_CODE
+    } else {
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use error::{FargoError, TRANSPORT_FAILED_EXIT_CODE, exit_code, is_transport_failure};
+    use failure::{Error, Fail, err_msg};
+
+    #[test]
+    fn test_exit_code() {
+        let transport_failed: Error =
+            FargoError::TransportFailed(String::from("connection refused")).into();
+        assert!(is_transport_failure(&transport_failed));
+        assert_eq!(exit_code(&transport_failed), TRANSPORT_FAILED_EXIT_CODE);
+
+        let with_context: Error = FargoError::TransportFailed(String::from("connection refused"))
+            .context("copying the test binary failed")
+            .into();
+        assert_eq!(exit_code(&with_context), TRANSPORT_FAILED_EXIT_CODE);
+
+        assert_eq!(exit_code(&FargoError::RemoteExit(255).into()), 1);
+        assert_eq!(exit_code(&err_msg("cargo not found")), 1);
+    }
+}
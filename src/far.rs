@@ -0,0 +1,105 @@
+// Copyright 2018 The Fuchsia Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use failure::Error;
+use std::collections::BTreeMap;
+use std::io::Write;
+
+static MAGIC: [u8; 8] = [0xc8, 0xbf, 0x0b, 0x48, 0xad, 0xab, 0xc5, 0x11];
+static DIR_CHUNK_TYPE: &[u8; 8] = b"DIR-----";
+static DIR_NAMES_CHUNK_TYPE: &[u8; 8] = b"DIRNAMES";
+
+const INDEX_ENTRY_LEN: u64 = 24;
+const DIR_ENTRY_LEN: u64 = 32;
+const CONTENT_ALIGNMENT: u64 = 4096;
+
+fn align(offset: u64, alignment: u64) -> u64 {
+    offset.div_ceil(alignment) * alignment
+}
+
+fn write_padding<W: Write>(writer: &mut W, from: u64, to: u64) -> Result<(), Error> {
+    writer.write_all(&vec![0; (to - from) as usize])?;
+    Ok(())
+}
+
+/// Writes a Fuchsia archive (FAR) containing `entries`, keyed by path.
+pub fn write_far<W: Write>(
+    entries: &BTreeMap<String, Vec<u8>>,
+    writer: &mut W,
+) -> Result<(), Error> {
+    let index_len = 2 * INDEX_ENTRY_LEN;
+    let dir_offset = MAGIC.len() as u64 + 8 + index_len;
+    let dir_len = entries.len() as u64 * DIR_ENTRY_LEN;
+    let names_offset = dir_offset + dir_len;
+    let names_len = align(entries.keys().map(|name| name.len() as u64).sum(), 8);
+
+    writer.write_all(&MAGIC)?;
+    writer.write_u64::<LittleEndian>(index_len)?;
+    for &(chunk_type, offset, len) in &[
+        (DIR_CHUNK_TYPE, dir_offset, dir_len),
+        (DIR_NAMES_CHUNK_TYPE, names_offset, names_len),
+    ] {
+        writer.write_all(chunk_type)?;
+        writer.write_u64::<LittleEndian>(offset)?;
+        writer.write_u64::<LittleEndian>(len)?;
+    }
+
+    // Entries are sorted by path, as the format requires, and each file starts at
+    // an aligned offset.
+    let mut name_offset = 0;
+    let mut content_offset = align(names_offset + names_len, CONTENT_ALIGNMENT);
+    for (name, content) in entries {
+        writer.write_u32::<LittleEndian>(name_offset as u32)?;
+        writer.write_u16::<LittleEndian>(name.len() as u16)?;
+        writer.write_u16::<LittleEndian>(0)?;
+        writer.write_u64::<LittleEndian>(content_offset)?;
+        writer.write_u64::<LittleEndian>(content.len() as u64)?;
+        writer.write_u64::<LittleEndian>(0)?;
+        name_offset += name.len() as u64;
+        content_offset = align(content_offset + content.len() as u64, CONTENT_ALIGNMENT);
+    }
+
+    for name in entries.keys() {
+        writer.write_all(name.as_bytes())?;
+    }
+    write_padding(writer, names_offset + name_offset, names_offset + names_len)?;
+
+    let mut offset = names_offset + names_len;
+    for content in entries.values() {
+        let content_offset = align(offset, CONTENT_ALIGNMENT);
+        write_padding(writer, offset, content_offset)?;
+        writer.write_all(content)?;
+        offset = content_offset + content.len() as u64;
+    }
+    write_padding(writer, offset, align(offset, CONTENT_ALIGNMENT))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use far::write_far;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_write_far() {
+        let mut entries = BTreeMap::new();
+        entries.insert(String::from("meta/package"), b"{}".to_vec());
+        entries.insert(String::from("bin/app"), b"ELF".to_vec());
+        let mut far = vec![];
+        write_far(&entries, &mut far).unwrap();
+
+        assert_eq!(far.len(), 3 * 4096);
+        assert_eq!(&far[0..8], &[0xc8, 0xbf, 0x0b, 0x48, 0xad, 0xab, 0xc5, 0x11]);
+        assert_eq!(&far[16..24], b"DIR-----");
+        assert_eq!(&far[40..48], b"DIRNAMES");
+        // The directory starts at 64 and holds "bin/app" before "meta/package".
+        assert_eq!(&far[64..68], &[0, 0, 0, 0]);
+        assert_eq!(&far[68..70], &[7, 0]);
+        assert_eq!(&far[72..80], &[0, 0x10, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&far[128..147], b"bin/appmeta/package");
+        assert_eq!(&far[4096..4099], b"ELF");
+        assert_eq!(&far[8192..8194], b"{}");
+    }
+}
@@ -0,0 +1,202 @@
+// Copyright 2018 The Fuchsia Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use failure::{Error, ResultExt};
+use libtest::{TestMessage, TestOutcome, TestResult, TestSummary};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if (c as u32) < 0x20 => (),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn format_test_case(result: &TestResult) -> String {
+    let mut test_case = format!(
+        "    <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\"",
+        escape_xml(&result.name),
+        escape_xml(&result.binary),
+        result.duration
+    );
+    match result.outcome {
+        TestOutcome::Failed => {
+            test_case.push_str(">\n      <failure message=\"test failed\">");
+            test_case.push_str(&escape_xml(result.stdout.as_ref().map_or("", String::as_str)));
+            test_case.push_str("</failure>\n    </testcase>\n");
+        }
+        TestOutcome::Ignored => test_case.push_str(">\n      <skipped/>\n    </testcase>\n"),
+        TestOutcome::Ok | TestOutcome::Measured => test_case.push_str("/>\n"),
+    }
+    test_case
+}
+
+/// The number of testcase elements of the testsuite of a binary, counting the one
+/// that stands for a hang.
+fn test_count(summary: &TestSummary) -> usize {
+    summary.passed + summary.failed + summary.ignored + summary.measured +
+        if summary.timed_out { 1 } else { 0 }
+}
+
+/// Formats the results gathered from every test binary as a JUnit XML document,
+/// with one `testsuite` element per test binary.
+pub fn format_junit_report(messages: &[TestMessage]) -> String {
+    let summaries: Vec<&TestSummary> = messages
+        .iter()
+        .filter_map(|message| match *message {
+            TestMessage::Summary(ref summary) => Some(summary),
+            TestMessage::Test(_) => None,
+        })
+        .collect();
+    let results: Vec<&TestResult> = messages
+        .iter()
+        .filter_map(|message| match *message {
+            TestMessage::Test(ref result) => Some(result),
+            TestMessage::Summary(_) => None,
+        })
+        .collect();
+
+    let total = |f: fn(&TestSummary) -> usize| summaries.iter().map(|s| f(s)).sum::<usize>();
+    let mut report = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    report.push_str(&format!(
+        "<testsuites tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{:.3}\">\n",
+        total(test_count),
+        total(|s| s.failed),
+        total(|s| s.ignored),
+        summaries.iter().map(|s| s.duration).sum::<f64>()
+    ));
+    for summary in summaries {
+        let hostname = summary
+            .device
+            .as_ref()
+            .map_or(String::new(), |device| format!(" hostname=\"{}\"", escape_xml(device)));
+        let errors = if summary.timed_out { " errors=\"1\"" } else { "" };
+        report.push_str(&format!(
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" \
+             time=\"{:.3}\"{}{}>\n",
+            escape_xml(&summary.binary),
+            test_count(summary),
+            summary.failed,
+            summary.ignored,
+            summary.duration,
+            errors,
+            hostname
+        ));
+        for result in results
+            .iter()
+            .filter(|r| r.binary == summary.binary && r.device == summary.device)
+        {
+            report.push_str(&format_test_case(result));
+        }
+        if summary.timed_out {
+            // The test that hung never reported a result, so the hang is reported as an
+            // error of the binary as a whole.
+            report.push_str(&format!(
+                "    <testcase name=\"timeout\" classname=\"{}\">\n      \
+                 <error message=\"test binary timed out\"/>\n    </testcase>\n",
+                escape_xml(&summary.binary)
+            ));
+        }
+        report.push_str("  </testsuite>\n");
+    }
+    report.push_str("</testsuites>\n");
+    report
+}
+
+pub fn write_junit_report(messages: &[TestMessage], junit_path: &Path) -> Result<(), Error> {
+    let mut junit_file =
+        File::create(junit_path).context(format!("unable to create {:?}", junit_path))?;
+    junit_file.write_all(format_junit_report(messages).as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use junit::format_junit_report;
+    use libtest::{TestMessage, TestOutcome, TestResult, TestSummary};
+
+    #[test]
+    fn test_format_junit_report() {
+        let messages = vec![
+            TestMessage::Test(TestResult {
+                name: String::from("tests::noop_test"),
+                binary: String::from("fargo_test-1234"),
+                outcome: TestOutcome::Ok,
+                duration: 0.25,
+                stdout: None,
+                device: None,
+                retries: 0,
+            }),
+            TestMessage::Test(TestResult {
+                name: String::from("tests::bad_test"),
+                binary: String::from("fargo_test-1234"),
+                outcome: TestOutcome::Failed,
+                duration: 0.5,
+                stdout: Some(String::from("assertion failed: a < b & c")),
+                device: None,
+                retries: 0,
+            }),
+            TestMessage::Summary(TestSummary {
+                binary: String::from("fargo_test-1234"),
+                passed: 1,
+                failed: 1,
+                duration: 1.0,
+                ..TestSummary::default()
+            }),
+        ];
+        let report = format_junit_report(&messages);
+        assert!(report.contains(
+            "<testsuites tests=\"2\" failures=\"1\" skipped=\"0\" time=\"1.000\">"
+        ));
+        assert!(report.contains(
+            "<testcase name=\"tests::noop_test\" classname=\"fargo_test-1234\" time=\"0.250\"/>"
+        ));
+        assert!(report.contains(
+            "<failure message=\"test failed\">assertion failed: a &lt; b &amp; c</failure>"
+        ));
+    }
+
+    #[test]
+    fn test_format_junit_report_timeout() {
+        let messages = vec![
+            TestMessage::Test(TestResult {
+                name: String::from("tests::noop_test"),
+                binary: String::from("hang-5678"),
+                outcome: TestOutcome::Ok,
+                duration: 0.25,
+                stdout: None,
+                device: None,
+                retries: 0,
+            }),
+            TestMessage::Summary(TestSummary {
+                binary: String::from("hang-5678"),
+                passed: 1,
+                duration: 60.0,
+                timed_out: true,
+                ..TestSummary::default()
+            }),
+        ];
+        let report = format_junit_report(&messages);
+        assert!(report.contains(
+            "<testsuites tests=\"2\" failures=\"0\" skipped=\"0\" time=\"60.000\">"
+        ));
+        assert!(report.contains(
+            "<testsuite name=\"hang-5678\" tests=\"2\" failures=\"0\" skipped=\"0\" \
+             time=\"60.000\" errors=\"1\">"
+        ));
+        assert!(report.contains("<error message=\"test binary timed out\"/>"));
+    }
+}
@@ -3,88 +3,461 @@
 // found in the LICENSE file.
 
 //! While fargo is mainly intended to be a command line tool, this library
-//! exposes one function, `run_cargo`, that could be integrated directly into
-//! Rust programs that want to cross compile cargo crates on Fuchsia.
+//! exposes `CargoInvocation`, and the simpler `run_cargo` function, that could
+//! be integrated directly into Rust programs that want to cross compile cargo
+//! crates on Fuchsia. Failures that callers might want to handle are reported
+//! as `FargoError` values.
 
 #![recursion_limit = "1024"]
 
+extern crate atty;
+extern crate byteorder;
 extern crate clap;
 #[macro_use]
 extern crate failure;
+extern crate libc;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
+extern crate sha2;
+#[cfg(feature = "native-ssh")]
+extern crate ssh2;
 extern crate toml;
 extern crate uname;
 
+mod component;
+mod config;
 mod device;
 mod cross;
+mod deploy;
+mod error;
 mod facade;
+mod far;
+mod junit;
+mod libtest;
+mod merkle;
+mod metadata;
+#[cfg(feature = "native-ssh")]
+mod native_ssh;
+mod netboot;
+mod package;
+mod registry;
 mod sdk;
+#[cfg(test)]
+pub(crate) mod test_util;
 mod utils;
 
-use clap::{App, AppSettings, Arg, SubCommand};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use component::ComponentConfig;
+use config::FargoConfig;
 use cross::{pkg_config_path, run_configure, run_pkg_config};
-use device::{enable_networking, netaddr, netls, scp_to_device, ssh, start_emulator, stop_emulator};
+use deploy::{DeployManifest, deploy};
+use device::{ControlMaster, ControlMasterOptions, Transport, connect, discovered_device_names,
+             enable_networking, list_devices, parse_device_address, select_device, ssh,
+             start_control_master, start_emulator, stop_emulator};
 use facade::create_facade;
 use failure::{Error, ResultExt, err_msg};
+use junit::write_junit_report;
+use libtest::{LibtestParser, MessageFormat, Shard, TestFilter, TestMessage, TestRun,
+              TestSummary, TestTimeouts, append_to_report, parse_test_list, read_report};
+use metadata::{CargoLine, CargoMetadata, parse_cargo_line};
+use package::{FuchsiaPackage, build_package, component_url, install_package};
+use registry::{DeviceRegistry, RegisteredDevice};
 use sdk::{FuchsiaConfig, cargo_out_dir, clang_archiver_path, clang_c_compiler_path,
           clang_cpp_compiler_path, clang_linker_path, clang_ranlib_path, sysroot_path,
           target_gen_dir};
+pub use error::{FargoError, TRANSPORT_FAILED_EXIT_CODE, exit_code, is_transport_failure};
+pub use metadata::{CargoArtifact, Target};
 pub use sdk::TargetOptions;
+use std::collections::VecDeque;
+use std::env;
 use std::fs;
-use std::path::PathBuf;
-use std::process::Command;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Instant, UNIX_EPOCH};
 use utils::strip_binary;
 
 fn copy_to_target(
-    source_path: &PathBuf,
     verbose: bool,
+    source_path: &Path,
     target_options: &TargetOptions,
+    transport: &mut dyn Transport,
 ) -> Result<String, Error> {
-    let netaddr = netaddr(verbose, target_options)?;
-    if verbose {
-        println!("netaddr {}", netaddr);
-    }
     let destination_path = format!("/tmp/{}", source_path.file_name().unwrap().to_string_lossy());
-    println!("copying {} to {}", source_path.to_string_lossy(), destination_path);
-    scp_to_device(verbose, target_options, &netaddr, &source_path, &destination_path)?;
+    let mut manifest = DeployManifest::load(target_options)?;
+    deploy(verbose, source_path, &destination_path, &mut manifest, transport)?;
     Ok(destination_path)
 }
 
+fn run_checked(transport: &mut dyn Transport, command: &str) -> Result<(), Error> {
+    let exit_code = transport.run(command)?;
+    if exit_code != 0 {
+        return Err(FargoError::RemoteExit(exit_code).into());
+    }
+    Ok(())
+}
+
+/// The exit code of `run-on-target` for a test binary that timed out, the same one
+/// timeout(1) uses.
+const TIMEOUT_EXIT_CODE: i32 = 124;
+
+/// How the test binaries of a test run are run and how their results are reported.
+#[derive(Debug, Default)]
+struct TestOptions<'a> {
+    /// Arguments for libtest, such as the names of the tests to run.
+    test_params: Vec<&'a str>,
+    /// Arguments passed to the tests after `--`.
+    test_args: Option<&'a str>,
+    message_format: MessageFormat,
+    junit_path: Option<&'a Path>,
+    filter: TestFilter,
+    timeouts: TestTimeouts,
+    retries: usize,
+}
+
+/// Returns whether the test run is out of time, which leaves `filename` unrun.
+fn out_of_time(filename: &str, timeouts: &TestTimeouts) -> bool {
+    if timeouts.remaining() == Some(Duration::from_secs(0)) {
+        eprintln!("not running {}, the test run timed out", filename);
+        return true;
+    }
+    false
+}
+
 fn run_program_on_target(
     filename: &str,
     verbose: bool,
     target_options: &TargetOptions,
     set_root_view: bool,
-    params: &[&str],
-    test_args: Option<&str>,
-) -> Result<(), Error> {
+    report_path: Option<&Path>,
+    test_options: &TestOptions,
+) -> Result<i32, Error> {
+    if out_of_time(filename, &test_options.timeouts) {
+        return Ok(TIMEOUT_EXIT_CODE);
+    }
+    let mut transport = connect(verbose, target_options)?;
+    run_program_with_transport(
+        &mut transport,
+        filename,
+        verbose,
+        target_options,
+        set_root_view,
+        report_path,
+        test_options,
+    )
+}
+
+/// Copies the program `filename` to the target and runs it over `transport`,
+/// reconnecting if the connection is lost while running tests.
+fn run_program_with_transport<'a>(
+    transport: &mut Box<dyn Transport + 'a>,
+    filename: &str,
+    verbose: bool,
+    target_options: &'a TargetOptions,
+    set_root_view: bool,
+    report_path: Option<&Path>,
+    test_options: &TestOptions,
+) -> Result<i32, Error> {
+    let test_args = test_options.test_args;
+    let message_format = test_options.message_format;
+    let test_filter = &test_options.filter;
+    let timeout = test_options.timeouts.remaining();
+    let retries = test_options.retries;
+
     let source_path = PathBuf::from(&filename);
     let stripped_source_path = strip_binary(&source_path, target_options)?;
-    let destination_path = copy_to_target(&stripped_source_path, verbose, target_options)?;
+    let destination_path =
+        copy_to_target(verbose, &stripped_source_path, target_options, &mut **transport)?;
+
+    let mut libtest_args: Vec<String> =
+        test_options.test_params.iter().map(|param| param.to_string()).collect();
+    libtest_args.extend(test_filter.libtest_args());
+    let binary_name = source_path.file_name().unwrap().to_string_lossy().into_owned();
+    if let Some(shard) = test_filter.shard {
+        let selected_tests = list_tests(&mut **transport, &destination_path, &libtest_args)?;
+        let tests = shard.select(&selected_tests);
+        if tests.is_empty() {
+            eprintln!(
+                "no tests of {} in shard {} of {}",
+                destination_path,
+                shard.index,
+                shard.count
+            );
+            // The binary still shows up in the results, having run no tests.
+            let summary = TestSummary {
+                binary: binary_name,
+                filtered_out: selected_tests.len(),
+                ..TestSummary::default()
+            };
+            report_messages(&[TestMessage::Summary(summary)], message_format, report_path)?;
+            return Ok(0);
+        }
+        let all_tests = list_tests(&mut **transport, &destination_path, &[])?;
+        libtest_args = test_filter.only_libtest_args(&all_tests, &tests);
+    }
+
+    let command_string =
+        test_command_string(set_root_view, &destination_path, &libtest_args, test_args);
+
+    if verbose {
+        println!("running {}", command_string);
+    }
+
+    if set_root_view {
+        transport.run("killall scene_manager").unwrap_or(0);
+        transport.run("killall set_root_view").unwrap_or(0);
+    }
+
+    let parse_results = message_format == MessageFormat::Json || report_path.is_some() ||
+        timeout.is_some() || retries > 0;
+    if !parse_results {
+        return transport.run(&command_string);
+    }
+
+    let (exit_code, test_run) = run_test_binary_with_retries(
+        transport,
+        &mut || connect(verbose, target_options),
+        &binary_name,
+        &destination_path,
+        set_root_view,
+        &libtest_args,
+        test_options,
+    )?;
+    {
+        let passed_on_retry = test_run.passed_on_retry();
+        if !passed_on_retry.is_empty() {
+            eprintln!(
+                "tests of {} that passed only on retry: {}",
+                binary_name,
+                passed_on_retry.join(", ")
+            );
+        }
+    }
+    report_messages(&test_run.into_messages(), message_format, report_path)?;
+    Ok(exit_code)
+}
+
+/// Runs a test binary on the target with `libtest_args`. If the connection to the
+/// device is lost, the binary is run again over a new connection from `reconnect`.
+/// Once the binary ran to the end, the tests that failed are run again. Either is
+/// done at most `retries` times in all. Returns the exit code of the last run along
+/// with the results of all the runs.
+fn run_test_binary_with_retries<'a>(
+    transport: &mut Box<dyn Transport + 'a>,
+    reconnect: &mut dyn FnMut() -> Result<Box<dyn Transport + 'a>, Error>,
+    binary_name: &str,
+    binary_path: &str,
+    set_root_view: bool,
+    libtest_args: &[String],
+    test_options: &TestOptions,
+) -> Result<(i32, TestRun), Error> {
+    let timeouts = &test_options.timeouts;
+    let retries = test_options.retries;
+    let mut test_run: Option<TestRun> = None;
+    let mut all_tests = None;
+    let mut exit_code = 0;
+    let mut transport_failures = 0;
+    for attempt in 0..retries + 1 {
+        // Once the binary has run to the end, only the tests that failed are run again.
+        let attempt_libtest_args = match test_run {
+            Some(ref test_run) => {
+                if all_tests.is_none() {
+                    all_tests = Some(list_tests(&mut **transport, binary_path, &[])?);
+                }
+                test_options
+                    .filter
+                    .only_libtest_args(all_tests.as_ref().unwrap(), &test_run.failed_tests())
+            }
+            None => libtest_args.to_vec(),
+        };
+        let command_string = test_command_string(
+            set_root_view,
+            binary_path,
+            &attempt_libtest_args,
+            test_options.test_args,
+        );
+        let (attempt_exit_code, attempt_run) = match run_test_binary(
+            &mut **transport,
+            binary_name,
+            binary_path,
+            &command_string,
+            test_options.message_format,
+            timeouts.remaining(),
+        ) {
+            Ok(attempt) => attempt,
+            Err(error) => {
+                if !is_transport_failure(&error) || attempt == retries {
+                    return Err(error);
+                }
+                eprintln!(
+                    "lost the connection to the device while running {}, running it again: {}",
+                    binary_name,
+                    error
+                );
+                transport_failures += 1;
+                *transport = reconnect()?;
+                continue;
+            }
+        };
+        exit_code = attempt_exit_code;
+        if let Some(ref mut test_run) = test_run {
+            test_run.merge_retry(attempt_run, attempt);
+        } else {
+            test_run = Some(attempt_run);
+        }
+
+        let run = test_run.as_ref().unwrap();
+        let out_of_time = timeouts.remaining() == Some(Duration::from_secs(0));
+        if exit_code == 0 || run.summary.timed_out || run.failed_tests().is_empty() ||
+            out_of_time
+        {
+            break;
+        }
+        if attempt < retries {
+            eprintln!("running the failed tests of {} again", binary_name);
+        }
+    }
+
+    let mut test_run = test_run.unwrap();
+    test_run.summary.transport_failures = transport_failures;
+    Ok((exit_code, test_run))
+}
+
+/// Prints the results of a test binary in the JSON message format and appends them
+/// to the report of the test run.
+fn report_messages(
+    messages: &[TestMessage],
+    message_format: MessageFormat,
+    report_path: Option<&Path>,
+) -> Result<(), Error> {
+    if message_format == MessageFormat::Json {
+        for message in messages {
+            println!("{}", message.to_json()?);
+        }
+    }
+    if let Some(report_path) = report_path {
+        append_to_report(report_path, messages)?;
+    }
+    Ok(())
+}
+
+/// Returns the arguments that make the runner run the test binaries as `test_options`
+/// say, apart from the message format and the report.
+fn test_options_runner_args(test_options: &TestOptions) -> Vec<String> {
+    let mut runner_args = test_options.filter.runner_args();
+    runner_args.extend(test_options.timeouts.runner_args());
+    if test_options.retries > 0 {
+        runner_args.push(format!("--retries={}", test_options.retries));
+    }
+    if let Some(test_args) = test_options.test_args {
+        runner_args.extend(test_args_runner_args(test_args));
+    }
+    runner_args
+}
+
+/// Passes `test_args` to the runner one word at a time, as cargo splits the runner
+/// command at whitespace. The runner joins them back together.
+fn test_args_runner_args(test_args: &str) -> Vec<String> {
+    test_args.split_whitespace().map(|arg| format!("--args={}", arg)).collect()
+}
+
+/// Returns the shell command that runs the binary at `binary_path` on the target.
+fn test_command_string(
+    set_root_view: bool,
+    binary_path: &str,
+    libtest_args: &[String],
+    test_args: Option<&str>,
+) -> String {
     let mut command_string = (if set_root_view { "set_root_view " } else { "" }).to_string();
-    command_string.push_str(&destination_path);
-    for param in params {
+    command_string.push_str(binary_path);
+    for arg in libtest_args {
         command_string.push(' ');
-        command_string.push_str(param);
+        command_string.push_str(arg);
     }
 
     if let Some(test_args_str) = test_args {
         command_string.push_str(" -- ");
         command_string.push_str(test_args_str);
     }
+    command_string
+}
 
-    if verbose {
-        println!("running {}", command_string);
+/// Returns the names of the tests a test binary on the target runs with `libtest_args`.
+fn list_tests(
+    transport: &mut dyn Transport,
+    binary_path: &str,
+    libtest_args: &[String],
+) -> Result<Vec<String>, Error> {
+    let mut command_string = format!("{} --list", binary_path);
+    for arg in libtest_args {
+        command_string.push(' ');
+        command_string.push_str(arg);
     }
-
-    if set_root_view {
-        ssh(verbose, target_options, "killall scene_manager").unwrap_or(());
-        ssh(verbose, target_options, "killall set_root_view").unwrap_or(());
+    let mut listing = String::new();
+    let exit_code = transport.run_with_output(&command_string, &mut |line| {
+        listing.push_str(line);
+        listing.push('\n');
+        Ok(())
+    })?;
+    if exit_code != 0 {
+        return Err(FargoError::RemoteExit(exit_code).into());
     }
+    Ok(parse_test_list(&listing))
+}
 
-    ssh(verbose, target_options, &command_string)?;
-    Ok(())
+/// Runs a test binary on the target once, parsing its libtest output. With the JSON
+/// message format the output is echoed to stderr, otherwise to stdout. Returns the
+/// exit code of the test binary along with its results.
+///
+/// A binary still running once `timeout` has passed is killed and reported as hung,
+/// along with the results of the tests it finished.
+fn run_test_binary(
+    transport: &mut dyn Transport,
+    binary_name: &str,
+    binary_path: &str,
+    command_string: &str,
+    message_format: MessageFormat,
+    timeout: Option<Duration>,
+) -> Result<(i32, TestRun), Error> {
+    let start = Instant::now();
+    let mut parser = LibtestParser::new(binary_name);
+    let exit_code = {
+        let mut on_line = |line: &str| -> Result<(), Error> {
+            if message_format == MessageFormat::Json {
+                eprintln!("{}", line);
+            } else {
+                println!("{}", line);
+            }
+            parser.parse_line(line, start.elapsed());
+            Ok(())
+        };
+        match timeout {
+            Some(timeout) => transport.run_with_timeout(command_string, &mut on_line, timeout)?,
+            None => Some(transport.run_with_output(command_string, &mut on_line)?),
+        }
+    };
+
+    let (results, mut summary) = parser.finish(start.elapsed());
+    let exit_code = match exit_code {
+        Some(exit_code) => exit_code,
+        None => {
+            // Giving up on the command does not stop the binary on the device.
+            let process_name = Path::new(binary_path).file_name().unwrap().to_string_lossy();
+            transport.run(&format!("killall {}", process_name)).unwrap_or(0);
+            eprintln!(
+                "{} was stopped after running for {} seconds, it may be hung",
+                binary_name,
+                start.elapsed().as_secs()
+            );
+            summary.timed_out = true;
+            TIMEOUT_EXIT_CODE
+        }
+    };
+    let test_run = TestRun { results, summary };
+    Ok((exit_code, test_run))
 }
 
 extern crate notify;
@@ -93,7 +466,12 @@ use notify::{RecommendedWatcher, RecursiveMode, Watcher};
 use std::sync::mpsc::channel;
 use std::time::Duration;
 
-fn autotest(verbose: bool, release: bool, target_options: &TargetOptions) -> Result<(), Error> {
+fn autotest(
+    verbose: bool,
+    release: bool,
+    target_options: &TargetOptions,
+    runner: Option<PathBuf>,
+) -> Result<(), Error> {
     let (tx, rx) = channel();
     let mut watcher: RecommendedWatcher =
         Watcher::new(tx, Duration::from_secs(1)).context("autotest: watcher creation failed")?;
@@ -101,7 +479,11 @@ fn autotest(verbose: bool, release: bool, target_options: &TargetOptions) -> Res
     let cwd = std::fs::canonicalize(std::env::current_dir()?).context(
         "autotest: canonicalize working directory",
     )?;
-    let tgt = cwd.join("target");
+    // In a workspace the target directory is usually outside of the member being tested.
+    let tgt = match CargoMetadata::load(verbose) {
+        Ok(metadata) => metadata.target_directory,
+        Err(_) => cwd.join("target"),
+    };
     let git = cwd.join(".git");
 
     watcher.watch(&cwd, RecursiveMode::Recursive).context("autotest: watch failed")?;
@@ -118,7 +500,15 @@ fn autotest(verbose: bool, release: bool, target_options: &TargetOptions) -> Res
                 // TODO(raggi): provide a fuller ignore flag/pattern match solution here.
                 if !path.starts_with(&tgt) && !path.starts_with(&git) {
                     println!("autotest: running tests because {:?}", path);
-                    run_tests(verbose, release, false, target_options, "", &[], None).ok();
+                    run_tests(
+                        verbose,
+                        release,
+                        false,
+                        target_options,
+                        runner.clone(),
+                        &[],
+                        &TestOptions::default(),
+                    ).ok();
                 }
             }
             _ => {}
@@ -130,9 +520,18 @@ fn build_tests(
     verbose: bool,
     release: bool,
     target_options: &TargetOptions,
-    test_target: &str,
+    runner: Option<PathBuf>,
+    params: &[&str],
 ) -> Result<bool, Error> {
-    run_tests(verbose, release, true, target_options, test_target, &[], None)?;
+    run_tests(
+        verbose,
+        release,
+        true,
+        target_options,
+        runner,
+        params,
+        &TestOptions::default(),
+    )?;
     Ok(true)
 }
 
@@ -141,212 +540,1369 @@ fn run_tests(
     release: bool,
     no_run: bool,
     target_options: &TargetOptions,
-    test_target: &str,
+    runner: Option<PathBuf>,
     params: &[&str],
-    target_params: Option<&str>,
+    test_options: &TestOptions,
 ) -> Result<(), Error> {
+    // The built-in ssh client can't share its session with the runners cargo starts.
+    if target_options.native_ssh && !no_run {
+        return run_tests_over_one_connection(
+            verbose,
+            release,
+            target_options,
+            runner,
+            params,
+            test_options,
+        );
+    }
+
+    let timeouts = &test_options.timeouts;
+    let junit_path = test_options.junit_path;
 
     let mut args = vec!["test"];
 
-    if !test_target.is_empty() {
-        args.push("--test");
-        args.push(test_target);
+    if no_run {
+        args.push("--no-run");
+    }
+
+    // A binary that hangs is stopped and fails, which should not keep the binaries
+    // after it from running.
+    if timeouts.is_set() {
+        args.push("--no-fail-fast");
+    }
+
+    args.extend(params);
+    args.extend(&test_options.test_params);
+
+    // Cargo starts a runner for every test binary; sharing one multiplexed ssh connection
+    // between them avoids a connection and authentication for every copy and command.
+    let control_master = if no_run {
+        None
+    } else {
+        match ControlMasterOptions::new()
+            .and_then(|options| start_control_master(verbose, target_options, &options))
+        {
+            Ok(control_master) => Some(control_master),
+            Err(error) => {
+                eprintln!("warning: not sharing an ssh connection between tests: {}", error);
+                None
+            }
+        }
+    };
+    let mut runner_options = target_options.clone();
+    runner_options.control_path = control_master.as_ref().map(ControlMaster::control_path);
+
+    let mut invocation = cargo_invocation(&runner_options, &args);
+    invocation.verbose(verbose).release(release);
+    if let Some(runner) = runner {
+        invocation.runner(runner);
+    }
+
+    if test_options.message_format == MessageFormat::Json {
+        invocation.target_arg("--message-format=json");
+    }
+
+    // Cargo starts a separate runner process for every test binary, so each of them
+    // appends its results to a shared report that is turned into JUnit XML at the end.
+    let report_path = junit_path.map(|_| {
+        env::temp_dir().join(format!("fargo-test-report-{}.jsonl", process::id()))
+    });
+    if let Some(ref report_path) = report_path {
+        if report_path.exists() {
+            fs::remove_file(report_path)?;
+        }
+        invocation.target_arg(&format!("--report={}", report_path.to_string_lossy()));
+    }
+
+    for runner_arg in test_options_runner_args(test_options) {
+        invocation.target_arg(&runner_arg);
+    }
+
+    let cargo_result = invocation.run();
+
+    if let (Some(junit_path), Some(report_path)) = (junit_path, report_path) {
+        let messages = read_report(&report_path)?;
+        write_junit_report(&messages, junit_path)?;
+        fs::remove_file(&report_path).ok();
+        if verbose {
+            println!("wrote JUnit report to {:?}", junit_path);
+        }
+    }
+
+    let output = cargo_result?;
+    if !output.success() {
+        return Err(FargoError::CargoFailed(output.exit_code).into());
+    }
+    Ok(())
+}
+
+/// Builds the test binaries and runs them one after the other over a single
+/// connection to the target, rather than having cargo start a runner, and so open a
+/// connection, for every binary.
+fn run_tests_over_one_connection(
+    verbose: bool,
+    release: bool,
+    target_options: &TargetOptions,
+    runner: Option<PathBuf>,
+    cargo_params: &[&str],
+    test_options: &TestOptions,
+) -> Result<(), Error> {
+    let mut args = vec!["test", "--no-run"];
+    args.extend(cargo_params);
+    let mut invocation = cargo_invocation(target_options, &args);
+    invocation.verbose(verbose).release(release).collect_artifacts(true);
+    if let Some(runner) = runner {
+        invocation.runner(runner);
+    }
+    let output = invocation.run()?;
+    if !output.success() {
+        return Err(FargoError::CargoFailed(output.exit_code).into());
+    }
+    let binaries: Vec<&Path> =
+        output.artifacts.iter().filter_map(CargoArtifact::test_executable_path).collect();
+
+    let junit_path = test_options.junit_path;
+    let report_path = junit_path.map(|_| {
+        env::temp_dir().join(format!("fargo-test-report-{}.jsonl", process::id()))
+    });
+    if let Some(ref report_path) = report_path {
+        if report_path.exists() {
+            fs::remove_file(report_path)?;
+        }
+    }
+
+    let mut transport = connect(verbose, target_options)?;
+    let mut failures = vec![];
+    let mut result = Ok(());
+    for binary in binaries {
+        let filename = binary.to_string_lossy();
+        let binary_name = binary.file_name().unwrap().to_string_lossy().into_owned();
+        if out_of_time(&filename, &test_options.timeouts) {
+            failures.push(binary_name);
+            continue;
+        }
+        eprintln!("     Running {}", filename);
+        match run_program_with_transport(
+            &mut transport,
+            &filename,
+            verbose,
+            target_options,
+            false,
+            report_path.as_deref(),
+            test_options,
+        ) {
+            Ok(0) => (),
+            Ok(_) => failures.push(binary_name),
+            Err(error) => {
+                result = Err(error);
+                break;
+            }
+        }
+    }
+
+    if let (Some(junit_path), Some(report_path)) = (junit_path, report_path) {
+        let messages = read_report(&report_path)?;
+        write_junit_report(&messages, junit_path)?;
+        fs::remove_file(&report_path).ok();
+        if verbose {
+            println!("wrote JUnit report to {:?}", junit_path);
+        }
+    }
+
+    result?;
+    if !failures.is_empty() {
+        bail!("test binaries failed: {}", failures.join(", "));
     }
+    Ok(())
+}
+
+/// Runs the test binaries of one device, taking them from the queue shared with the
+/// other devices, and returns the binaries that failed. If the device can't be
+/// reached, the binary it took goes back to the queue for the other devices and the
+/// device takes no more binaries.
+fn run_device_tests(
+    device_name: &str,
+    runner_args: &[String],
+    test_params: &[String],
+    message_format: MessageFormat,
+    binaries: &Mutex<VecDeque<PathBuf>>,
+) -> Result<Vec<String>, Error> {
+    let mut failed = vec![];
+    loop {
+        let binary = match binaries.lock().unwrap().pop_front() {
+            Some(binary) => binary,
+            None => return Ok(failed),
+        };
+        let mut cmd = Command::new(&runner_args[0]);
+        cmd.args(&runner_args[1..]).arg(&binary).args(test_params).stdout(Stdio::piped());
+        let mut child = cmd.spawn().context(format!("unable to run {:?}", cmd))?;
+        let stdout = child.stdout.take().unwrap();
+        for line in BufReader::new(stdout).lines() {
+            let line = line?;
+            match message_format {
+                MessageFormat::Json => match serde_json::from_str::<TestMessage>(&line) {
+                    Ok(mut message) => {
+                        message.set_device(device_name);
+                        println!("{}", message.to_json()?);
+                    }
+                    Err(_) => println!("{}", line),
+                },
+                MessageFormat::Human => println!("[{}] {}", device_name, line),
+            }
+        }
+        let status = child.wait()?;
+        if status.code() == Some(TRANSPORT_FAILED_EXIT_CODE) {
+            eprintln!(
+                "unable to reach {}, leaving {} to the other devices",
+                device_name,
+                binary.to_string_lossy()
+            );
+            binaries.lock().unwrap().push_back(binary);
+            return Ok(failed);
+        }
+        if !status.success() {
+            failed.push(binary.file_name().unwrap().to_string_lossy().into_owned());
+        }
+    }
+}
+
+/// Builds the test binaries and spreads them over `device_names`, running the
+/// binaries of every device in parallel. Output and results are attributed to the
+/// device each binary ran on.
+fn run_tests_on_devices(
+    verbose: bool,
+    release: bool,
+    target_options: &TargetOptions,
+    runner: Option<PathBuf>,
+    cargo_params: &[&str],
+    test_options: &TestOptions,
+    device_names: &[String],
+) -> Result<(), Error> {
+    if device_names.is_empty() {
+        return Err(FargoError::DeviceNotFound(None).into());
+    }
+
+    let mut args = vec!["test", "--no-run"];
+    args.extend(cargo_params);
+    let mut invocation = cargo_invocation(target_options, &args);
+    invocation.verbose(verbose).release(release).collect_artifacts(true);
+    if let Some(ref runner) = runner {
+        invocation.runner(runner.clone());
+    }
+    let output = invocation.run()?;
+    if !output.success() {
+        return Err(FargoError::CargoFailed(output.exit_code).into());
+    }
+    let binaries: VecDeque<PathBuf> = output
+        .artifacts
+        .iter()
+        .filter_map(CargoArtifact::test_executable_path)
+        .map(Path::to_path_buf)
+        .collect();
+    if verbose {
+        println!("running {} test binaries on {}", binaries.len(), device_names.join(", "));
+    }
+    let binaries = Arc::new(Mutex::new(binaries));
+    let test_params: Vec<String> =
+        test_options.test_params.iter().map(|param| param.to_string()).collect();
+    let junit_path = test_options.junit_path;
+
+    let registry = DeviceRegistry::load()?;
+    let all_device_options: Vec<TargetOptions> = device_names
+        .iter()
+        .map(|device_name| {
+            let mut device_options = target_options.clone();
+            device_options.device_name = Some(device_name);
+            device_options.resolve_device(&registry);
+            device_options
+        })
+        .collect();
+    // The control masters are shut down when they are dropped, after all tests have run.
+    let control_masters: Vec<Option<ControlMaster>> = device_names
+        .iter()
+        .zip(&all_device_options)
+        .map(|(device_name, device_options)| {
+            if device_options.native_ssh {
+                return None;
+            }
+            match ControlMasterOptions::new()
+                .and_then(|options| start_control_master(verbose, device_options, &options))
+            {
+                Ok(control_master) => Some(control_master),
+                Err(error) => {
+                    eprintln!(
+                        "warning: not sharing an ssh connection with {}: {}",
+                        device_name,
+                        error
+                    );
+                    None
+                }
+            }
+        })
+        .collect();
+    let mut workers = vec![];
+    for ((device_name, device_options), control_master) in
+        device_names.iter().zip(&all_device_options).zip(&control_masters)
+    {
+        let mut device_options = device_options.clone();
+        device_options.control_path = control_master.as_ref().map(ControlMaster::control_path);
+        let report_path = junit_path.map(|_| {
+            env::temp_dir().join(format!(
+                "fargo-test-report-{}-{}.jsonl",
+                process::id(),
+                device_name
+            ))
+        });
+        let mut device_invocation = CargoInvocation::new(&device_options, &[]);
+        device_invocation.verbose(verbose);
+        if let Some(ref runner) = runner {
+            device_invocation.runner(runner.clone());
+        }
+        if test_options.message_format == MessageFormat::Json {
+            device_invocation.target_arg("--message-format=json");
+        }
+        if let Some(ref report_path) = report_path {
+            if report_path.exists() {
+                fs::remove_file(report_path)?;
+            }
+            device_invocation.target_arg(&format!("--report={}", report_path.to_string_lossy()));
+        }
+        for runner_arg in test_options_runner_args(test_options) {
+            device_invocation.target_arg(&runner_arg);
+        }
+        let runner_args = device_invocation.runner_args()?;
+
+        let device_name = device_name.clone();
+        let test_params = test_params.clone();
+        let message_format = test_options.message_format;
+        let binaries = binaries.clone();
+        workers.push(thread::spawn(move || {
+            let failed = run_device_tests(
+                &device_name,
+                &runner_args,
+                &test_params,
+                message_format,
+                &binaries,
+            );
+            (device_name, report_path, failed)
+        }));
+    }
+
+    // Every worker is waited for and its results are reported even if another one
+    // failed, so that a single broken device does not hide the results of the others.
+    let mut messages = vec![];
+    let mut failures = vec![];
+    let mut errors = vec![];
+    for worker in workers {
+        let (device_name, report_path, failed) = worker.join().unwrap();
+        if let Some(report_path) = report_path {
+            match read_report(&report_path) {
+                Ok(report) => for mut message in report {
+                    message.set_device(&device_name);
+                    messages.push(message);
+                },
+                Err(error) => errors.push(error),
+            }
+            fs::remove_file(&report_path).ok();
+        }
+        match failed {
+            Ok(failed) => for binary in failed {
+                failures.push(format!("{} on {}", binary, device_name));
+            },
+            Err(error) => errors.push(error.context(format!("on {}", device_name)).into()),
+        }
+    }
+    for binary in binaries.lock().unwrap().drain(..) {
+        failures.push(format!("{} on no device", binary.file_name().unwrap().to_string_lossy()));
+    }
+
+    if let Some(junit_path) = junit_path {
+        write_junit_report(&messages, junit_path)?;
+        if verbose {
+            println!("wrote JUnit report to {:?}", junit_path);
+        }
+    }
+
+    let mut errors = errors.into_iter();
+    if let Some(error) = errors.next() {
+        for error in errors {
+            eprintln!("{}", error);
+        }
+        return Err(error);
+    }
+    if !failures.is_empty() {
+        bail!("test binaries failed: {}", failures.join(", "));
+    }
+    Ok(())
+}
+
+fn build_binary(
+    verbose: bool,
+    release: bool,
+    target_options: &TargetOptions,
+    runner: Option<PathBuf>,
+    params: &[&str],
+) -> Result<(), Error> {
+    let mut args = vec!["build"];
+    for param in params {
+        args.push(param);
+    }
+
+    run_cargo(verbose, release, false, &args, target_options, runner, None)
+}
+
+fn run_binary(
+    verbose: bool,
+    release: bool,
+    set_root_view: bool,
+    target_options: &TargetOptions,
+    runner: Option<PathBuf>,
+    params: &[&str],
+) -> Result<i32, Error> {
+
+    let mut args = vec!["run"];
+    for param in params {
+        args.push(param);
+    }
+
+    let mut invocation = cargo_invocation(target_options, &args);
+    invocation.verbose(verbose).release(release).set_root_view(set_root_view);
+    if let Some(runner) = runner {
+        invocation.runner(runner);
+    }
+    Ok(invocation.run()?.exit_code)
+}
+
+fn build_packages(
+    verbose: bool,
+    release: bool,
+    target_options: &TargetOptions,
+    runner: Option<PathBuf>,
+    params: &[&str],
+    resources: &[String],
+) -> Result<Vec<FuchsiaPackage>, Error> {
+    let mut args = vec!["build"];
+    for param in params {
+        args.push(param);
+    }
+
+    let mut invocation = cargo_invocation(target_options, &args);
+    invocation.verbose(verbose).release(release).collect_artifacts(true);
+    if let Some(runner) = runner {
+        invocation.runner(runner);
+    }
+    let output = invocation.run()?;
+    if !output.success() {
+        return Err(FargoError::CargoFailed(output.exit_code).into());
+    }
+
+    let metadata = CargoMetadata::load(verbose)?;
+    let profile = invocation.profile_name();
+    let out_dir =
+        cargo_out_dir(&metadata.target_directory, target_options, profile).join("package");
+    let mut packages = vec![];
+    for artifact in &output.artifacts {
+        let executable_path = match artifact.executable_path() {
+            Some(executable_path) => executable_path,
+            None => continue,
+        };
+        let cargo_package = metadata
+            .packages
+            .iter()
+            .find(|package| package.id == artifact.package_id)
+            .ok_or_else(|| format_err!("unknown package {}", artifact.package_id))?;
+        let component = ComponentConfig::from_package_metadata(cargo_package.metadata.as_ref())
+            .context(format!("in package {}", cargo_package.name))?;
+        let stripped_path = strip_binary(&executable_path.to_path_buf(), target_options)?;
+        let package = build_package(
+            &artifact.target.name,
+            &stripped_path,
+            cargo_package.root(),
+            resources,
+            &component,
+            &out_dir,
+        )?;
+        println!("built package {}", package.archive_path.to_string_lossy());
+        packages.push(package);
+    }
+    if packages.is_empty() {
+        bail!("cargo did not build any binary to package");
+    }
+    Ok(packages)
+}
+
+fn run_package(
+    verbose: bool,
+    release: bool,
+    set_root_view: bool,
+    target_options: &TargetOptions,
+    runner: Option<PathBuf>,
+    params: &[&str],
+    resources: &[String],
+) -> Result<i32, Error> {
+    // The arguments after "--" are for the component, not for cargo.
+    let (params, program_args) = match params.iter().position(|&param| param == "--") {
+        Some(index) => (&params[..index], &params[index + 1..]),
+        None => (params, &[][..]),
+    };
+    let packages = build_packages(verbose, release, target_options, runner, params, resources)?;
+    if packages.len() > 1 {
+        let names: Vec<&str> = packages.iter().map(|package| package.name.as_str()).collect();
+        bail!(
+            "more than one binary was built, use --bin or --example to pick one of: {}",
+            names.join(", ")
+        );
+    }
+    let package = &packages[0];
+    let mut transport = connect(verbose, target_options)?;
+    install_package(verbose, package, &mut *transport)?;
+    let mut command_string = format!(
+        "{} {}",
+        if set_root_view { "set_root_view" } else { "run" },
+        component_url(&package.name)
+    );
+    for arg in program_args {
+        command_string.push(' ');
+        command_string.push_str(arg);
+    }
+    if verbose {
+        println!("running {}", command_string);
+    }
+    if set_root_view {
+        transport.run("killall scene_manager").unwrap_or(0);
+        transport.run("killall set_root_view").unwrap_or(0);
+    }
+    transport.run(&command_string)
+}
+
+fn load_driver(
+    verbose: bool,
+    release: bool,
+    target_options: &TargetOptions,
+    runner: Option<PathBuf>,
+    package_names: &[&str],
+    all: bool,
+) -> Result<(), Error> {
+    let metadata = CargoMetadata::load(verbose)?;
+    let packages = metadata.select_packages(package_names, all, &env::current_dir()?)?;
+    let drivers: Vec<_> = packages
+        .iter()
+        .filter_map(|package| package.cdylib_target().map(|target| (package, target)))
+        .collect();
+    if drivers.is_empty() {
+        bail!("no cdylib target to load as a driver found in the selected packages");
+    }
+
+    let mut args = vec!["build"];
+    for &(package, _) in &drivers {
+        args.push("--package");
+        args.push(&package.name);
+    }
+    let mut invocation = CargoInvocation::new(target_options, &args);
+    invocation.verbose(verbose).release(release).collect_artifacts(true);
+    if let Some(runner) = runner {
+        invocation.runner(runner);
+    }
+    let output = invocation.run()?;
+    if !output.success() {
+        return Err(FargoError::CargoFailed(output.exit_code).into());
+    }
+
+    let mut transport = connect(verbose, target_options)?;
+    for (package, target) in drivers {
+        let filename = output
+            .artifacts
+            .iter()
+            .filter(|artifact| artifact.package_id == package.id)
+            .filter_map(CargoArtifact::cdylib_path)
+            .next()
+            .ok_or_else(|| {
+                format_err!("cargo did not report building {}", target.cdylib_file_name())
+            })?
+            .to_path_buf();
+        let destination_path = copy_to_target(verbose, &filename, target_options, &mut *transport)?;
+        let command_string = format!("dm add-driver:{}", destination_path);
+        if verbose {
+            println!("running {}", command_string);
+        }
+        run_checked(&mut *transport, &command_string)?;
+    }
+    Ok(())
+}
+
+/// Runs the cargo tool configured to target Fuchsia. When used as a library,
+/// the runner options must contain the path to fargo or some other program
+/// that implements the `run-on-target` subcommand in a way compatible with
+/// fargo. `CargoInvocation` offers more options.
+///
+/// # Examples
+///
+/// ```
+/// use fargo::{run_cargo, TargetOptions};
+///
+/// let target_options = TargetOptions::new(true, "x64", None);
+/// run_cargo(false, true, false, &["--help"], &target_options, None, None);
+///
+/// ```
+pub fn run_cargo(
+    verbose: bool,
+    release: bool,
+    set_root_view: bool,
+    args: &[&str],
+    target_options: &TargetOptions,
+    runner: Option<PathBuf>,
+    additional_target_args: Option<&str>,
+) -> Result<(), Error> {
+    let mut invocation = CargoInvocation::new(target_options, args);
+    invocation.verbose(verbose).release(release).set_root_view(set_root_view);
+    if let Some(runner) = runner {
+        invocation.runner(runner);
+    }
+    if let Some(additional_target_args) = additional_target_args {
+        invocation.target_arg(additional_target_args);
+    }
+    let output = invocation.run()?;
+    if !output.success() {
+        return Err(FargoError::CargoFailed(output.exit_code).into());
+    }
+    Ok(())
+}
+
+/// The result of a cargo invocation that ran to completion.
+#[derive(Debug)]
+pub struct CargoOutput {
+    /// The exit code of cargo. When cargo runs a binary on the target, this is
+    /// the exit code of that binary.
+    pub exit_code: i32,
+    /// The files built by cargo, when collecting them was requested with
+    /// `CargoInvocation::collect_artifacts`.
+    pub artifacts: Vec<CargoArtifact>,
+}
+
+impl CargoOutput {
+    pub fn success(&self) -> bool {
+        self.exit_code == 0
+    }
+}
+
+/// Builder for running cargo configured to target Fuchsia.
+///
+/// # Examples
+///
+/// ```no_run
+/// use fargo::{CargoInvocation, TargetOptions};
+///
+/// let target_options = TargetOptions::new(true, "x64", None);
+/// let output = CargoInvocation::new(&target_options, &["test"])
+///     .release(true)
+///     .features(&["fidl"])
+///     .target_cpu("arm64")
+///     .env("RUST_BACKTRACE", "1")
+///     .target_arg("--args=--nocapture")
+///     .run()
+///     .unwrap();
+/// assert!(output.success());
+/// ```
+#[derive(Debug)]
+pub struct CargoInvocation<'a> {
+    target_options: TargetOptions<'a>,
+    args: Vec<String>,
+    verbose: bool,
+    release: bool,
+    profile: Option<String>,
+    features: Vec<String>,
+    no_default_features: bool,
+    set_root_view: bool,
+    runner: Option<PathBuf>,
+    env: Vec<(String, String)>,
+    target_args: Vec<String>,
+    collect_artifacts: bool,
+}
+
+impl<'a> CargoInvocation<'a> {
+    /// Creates an invocation of cargo with `args`, the first of which is the cargo
+    /// subcommand, i.e. `build`.
+    pub fn new(target_options: &TargetOptions<'a>, args: &[&str]) -> CargoInvocation<'a> {
+        CargoInvocation {
+            target_options: target_options.clone(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            verbose: false,
+            release: false,
+            profile: None,
+            features: vec![],
+            no_default_features: false,
+            set_root_view: false,
+            runner: None,
+            env: vec![],
+            target_args: vec![],
+            collect_artifacts: false,
+        }
+    }
+
+    pub fn verbose(&mut self, verbose: bool) -> &mut CargoInvocation<'a> {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Builds with the release profile if `release` is true, otherwise with the
+    /// default debug profile.
+    pub fn release(&mut self, release: bool) -> &mut CargoInvocation<'a> {
+        self.release = release;
+        self
+    }
+
+    /// Builds with the cargo profile named `profile`, which may be a custom one, instead
+    /// of the one `release` selects.
+    pub fn profile(&mut self, profile: &str) -> &mut CargoInvocation<'a> {
+        self.profile = Some(profile.to_string());
+        self
+    }
+
+    pub fn features(&mut self, features: &[&str]) -> &mut CargoInvocation<'a> {
+        self.features.extend(features.iter().map(|feature| feature.to_string()));
+        self
+    }
+
+    pub fn no_default_features(&mut self, no_default_features: bool) -> &mut CargoInvocation<'a> {
+        self.no_default_features = no_default_features;
+        self
+    }
+
+    /// Overrides the target CPU of the target options, i.e. `arm64`.
+    pub fn target_cpu(&mut self, target_cpu: &'a str) -> &mut CargoInvocation<'a> {
+        self.target_options = self.target_options.with_target_cpu(target_cpu);
+        self
+    }
+
+    /// Runs binaries on the target with `set_root_view`.
+    pub fn set_root_view(&mut self, set_root_view: bool) -> &mut CargoInvocation<'a> {
+        self.set_root_view = set_root_view;
+        self
+    }
+
+    /// Path of the program cargo uses to run binaries on the target. Defaults to the
+    /// current executable, which must then implement the `run-on-target` subcommand.
+    pub fn runner(&mut self, runner: PathBuf) -> &mut CargoInvocation<'a> {
+        self.runner = Some(runner);
+        self
+    }
+
+    /// Sets an additional environment variable for cargo.
+    pub fn env(&mut self, key: &str, value: &str) -> &mut CargoInvocation<'a> {
+        self.env.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Adds an argument for the `run-on-target` subcommand of the runner. Cargo splits
+    /// the runner command at whitespace, so `run` fails if the argument contains any.
+    pub fn target_arg(&mut self, target_arg: &str) -> &mut CargoInvocation<'a> {
+        self.target_args.push(target_arg.to_string());
+        self
+    }
+
+    /// Runs cargo with `--message-format=json` to collect the paths of the files it
+    /// builds. Compiler diagnostics are still printed to stderr.
+    pub fn collect_artifacts(&mut self, collect_artifacts: bool) -> &mut CargoInvocation<'a> {
+        self.collect_artifacts = collect_artifacts;
+        self
+    }
+
+    /// Returns the directory cargo puts the outputs of this invocation in, taking the
+    /// workspace, the configured target directory and the profile into account.
+    pub fn out_dir(&self) -> Result<PathBuf, Error> {
+        let metadata = CargoMetadata::load(self.verbose)?;
+        Ok(cargo_out_dir(&metadata.target_directory, &self.target_options, self.profile_name()))
+    }
+
+    fn profile_name(&self) -> Option<&str> {
+        match self.profile {
+            Some(ref profile) => Some(profile),
+            None if self.release => Some("release"),
+            None => None,
+        }
+    }
+
+    /// The program cargo runs binaries on the target with, followed by its arguments.
+    fn runner_args(&self) -> Result<Vec<String>, Error> {
+        let target_options = &self.target_options;
+        let fargo_path = match self.runner {
+            Some(ref runner) => runner.clone(),
+            None => fs::canonicalize(std::env::current_exe()?)?,
+        };
+
+        let mut runner_args = vec![
+            fargo_path
+                .to_str()
+                .ok_or_else(|| err_msg("unable to convert path to utf8 encoding"))?
+                .to_string(),
+        ];
+
+        if self.verbose {
+            runner_args.push(String::from("-v"));
+        }
+
+        runner_args.push(String::from("--target-cpu"));
+        runner_args.push(target_options.target_cpu.to_string());
+
+        // Both forms of the flags are passed so that the configuration the runner loads
+        // can't turn them back on.
+        runner_args.push(String::from(if target_options.native_ssh {
+            "--native-ssh"
+        } else {
+            "--no-native-ssh"
+        }));
+        runner_args.push(String::from(if target_options.release_os {
+            "--no-debug-os"
+        } else {
+            "--debug-os"
+        }));
+
+        if let Some(device_name) = target_options.device_name {
+            runner_args.push(String::from("--device-name"));
+            runner_args.push(device_name.to_string());
+        }
+
+        if let Some(device_address) = target_options.device_address {
+            runner_args.push(String::from("--device-addr"));
+            runner_args.push(device_address.to_string());
+        }
+
+        if let Some(control_path) = target_options.control_path {
+            runner_args.push(String::from("--control-path"));
+            runner_args.push(control_path.to_string_lossy().into_owned());
+        }
+
+        runner_args.push(String::from("run-on-target"));
+
+        if self.set_root_view {
+            runner_args.push(format!("--{}", SET_ROOT_VIEW));
+        }
+
+        runner_args.extend(self.target_args.iter().cloned());
+
+        Ok(runner_args)
+    }
+
+    /// The runner as cargo takes it, a single string that cargo splits at whitespace.
+    fn runner_command(&self) -> Result<String, Error> {
+        let runner_args = self.runner_args()?;
+        if let Some(arg) = runner_args.iter().find(|arg| arg.contains(char::is_whitespace)) {
+            bail!("cargo can't pass the runner argument {:?} as it contains whitespace", arg);
+        }
+        Ok(runner_args.join(" "))
+    }
+
+    /// The arguments cargo is run with. Arguments after a `--` in `args` are for the
+    /// binaries cargo runs, so they stay last.
+    fn cargo_args(&self) -> Vec<String> {
+        let binary_args_start =
+            self.args.iter().position(|arg| arg == "--").unwrap_or(self.args.len());
+        let mut cargo_args = self.args[..binary_args_start].to_vec();
+        cargo_args.push(String::from("--target"));
+        cargo_args.push(self.target_options.target_triple());
+
+        // Cargo doesn't take `--release` along with `--profile`.
+        if let Some(ref profile) = self.profile {
+            cargo_args.push(String::from("--profile"));
+            cargo_args.push(profile.clone());
+        } else if self.release {
+            cargo_args.push(String::from("--release"));
+        }
+
+        if !self.features.is_empty() {
+            cargo_args.push(String::from("--features"));
+            cargo_args.push(self.features.join(","));
+        }
+
+        if self.no_default_features {
+            cargo_args.push(String::from("--no-default-features"));
+        }
+
+        if self.collect_artifacts {
+            cargo_args.push(String::from("--message-format=json"));
+        }
+
+        cargo_args.extend(self.args[binary_args_start..].iter().cloned());
+        cargo_args
+    }
+
+    /// Runs cargo. A non-zero exit code of cargo is reported in the output rather
+    /// than as an error.
+    pub fn run(&self) -> Result<CargoOutput, Error> {
+        let verbose = self.verbose;
+        let target_options = &self.target_options;
+        let target_triple = target_options.target_triple();
+
+        let fargo_command = self.runner_command()?;
+
+        if verbose {
+            println!("fargo_command: {:?}", fargo_command);
+        }
+
+        let pkg_path = pkg_config_path(target_options)?;
+        let target_env_prefix =
+            format!("CARGO_TARGET_{}", target_triple.to_uppercase().replace("-", "_"));
+        let mut cmd = Command::new("cargo");
+
+        cmd.env(format!("{}_RUNNER", target_env_prefix), fargo_command)
+            .env(
+                format!("{}_RUSTFLAGS", target_env_prefix),
+                format!(
+                    "-C link-arg=--target={} \
+                    -C link-arg=--sysroot={}",
+                    target_triple,
+                    sysroot_path(target_options)?.to_str().unwrap()
+                ),
+            )
+            .env(
+                format!("{}_LINKER", target_env_prefix),
+                clang_linker_path(target_options)?.to_str().unwrap(),
+            )
+            .env("CC", clang_c_compiler_path(target_options)?.to_str().unwrap())
+            .env("CXX", clang_cpp_compiler_path(target_options)?.to_str().unwrap())
+            .env("AR", clang_archiver_path(target_options)?.to_str().unwrap())
+            .env("RANLIB", clang_ranlib_path(target_options)?.to_str().unwrap())
+            .env("PKG_CONFIG_ALL_STATIC", "1")
+            .env("PKG_CONFIG_ALLOW_CROSS", "1")
+            .env("PKG_CONFIG_PATH", "")
+            .env("PKG_CONFIG_LIBDIR", pkg_path)
+            .env("FUCHSIA_GEN_ROOT", target_gen_dir(target_options)?)
+            .envs(self.env.iter().cloned())
+            .args(self.cargo_args());
+
+        if verbose {
+            println!("cargo cmd: {:?}", cmd);
+        }
+
+        let mut artifacts = vec![];
+        let cargo_status = if self.collect_artifacts {
+            let mut child = cmd.stdout(Stdio::piped()).spawn().context("unable to run cargo")?;
+            let stdout = child.stdout.take().unwrap();
+            for line in BufReader::new(stdout).lines() {
+                let line = line?;
+                match parse_cargo_line(&line) {
+                    CargoLine::Artifact(artifact) => artifacts.push(artifact),
+                    CargoLine::Diagnostic(rendered) => eprint!("{}", rendered),
+                    CargoLine::Message => (),
+                    CargoLine::Output => println!("{}", line),
+                }
+            }
+            child.wait()?
+        } else {
+            cmd.status()?
+        };
+        match cargo_status.code() {
+            Some(exit_code) => Ok(CargoOutput {
+                exit_code,
+                artifacts,
+            }),
+            None => bail!("cargo exited with status {:?}", cargo_status),
+        }
+    }
+}
+
+static CREATE_FACADE: &str = "create-facade";
+static FIDL_PARAM: &str = "fidl_interface_path";
+static FIDL_PARAM_HELP: &str = "gn path and label (i.e. //garnet/public/lib/app/fidl:fidl) \
+for the new facade";
 
-    if no_run {
-        args.push("--no-run");
-    }
+static SET_ROOT_VIEW: &str = "set-root-view";
 
-    for param in params {
-        args.push(param);
-    }
+static MESSAGE_FORMAT: &str = "message-format";
 
-    if target_params.is_some() {
-        let formatted_target_params = format!("--args={}", target_params.unwrap());
-        run_cargo(
-            verbose,
-            release,
-            false,
-            &args,
-            target_options,
-            None,
-            Some(&formatted_target_params),
-        )?;
-    } else {
-        run_cargo(verbose, release, false, &args, target_options, None, None)?;
-    }
+static PACKAGE_MODE: &str = "package-mode";
 
-    Ok(())
+fn message_format_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(MESSAGE_FORMAT)
+        .long(MESSAGE_FORMAT)
+        .value_name("fmt")
+        .possible_values(&["human", "json"])
+        .default_value("human")
+        .help("Format of test results; json prints one JSON object per test and a summary")
 }
 
-fn build_binary(
-    verbose: bool,
-    release: bool,
-    target_options: &TargetOptions,
-    params: &[&str],
-) -> Result<(), Error> {
-    let mut args = vec!["build"];
-    for param in params {
-        args.push(param);
-    }
-
-    run_cargo(verbose, release, false, &args, target_options, None, None)
+fn release_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("release").long("release").help("Build release"),
+        Arg::with_name("no-release")
+            .long("no-release")
+            .conflicts_with("release")
+            .help("Build debug even if the configuration sets release"),
+    ]
 }
 
-fn run_binary(
-    verbose: bool,
-    release: bool,
-    set_root_view: bool,
-    target_options: &TargetOptions,
-    params: &[&str],
-) -> Result<(), Error> {
+fn test_filter_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("filter")
+            .long("filter")
+            .value_name("pattern")
+            .help("Only run tests whose names contain pattern"),
+        Arg::with_name("skip")
+            .long("skip")
+            .value_name("pattern")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Skip tests whose names contain pattern"),
+        Arg::with_name("exact")
+            .long("exact")
+            .help("Match test names exactly with --filter and --skip"),
+        Arg::with_name("ignored").long("ignored").help("Run only ignored tests"),
+        Arg::with_name("shard-index")
+            .long("shard-index")
+            .value_name("index")
+            .requires("shard-count")
+            .help("Run only the tests in this zero-based shard"),
+        Arg::with_name("shard-count")
+            .long("shard-count")
+            .value_name("count")
+            .requires("shard-index")
+            .help("Number of shards the tests of each binary are split into"),
+    ]
+}
 
-    let mut args = vec!["run"];
-    for param in params {
-        args.push(param);
-    }
+fn retries_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("retries")
+        .long("retries")
+        .value_name("count")
+        .default_value("0")
+        .help("Run failed tests, or binaries the connection failed for, again up to count times")
+}
 
-    run_cargo(verbose, release, set_root_view, &args, target_options, None, None)?;
-    Ok(())
+fn timeout_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("timeout")
+        .long("timeout")
+        .value_name("secs")
+        .help("Stop test binaries that run longer than secs and report them as hung")
 }
 
-fn load_driver(verbose: bool, release: bool, target_options: &TargetOptions) -> Result<(), Error> {
-    let args = vec!["build"];
-    run_cargo(verbose, release, false, &args, target_options, None, None)?;
-    let cwd = std::env::current_dir()?;
-    let package = cwd.file_name().ok_or(err_msg("No current directory"))?.to_str().ok_or(err_msg(
-        "Invalid current directory",
-    ))?;
-    let filename = cargo_out_dir(target_options)?.join(format!("lib{}.so", package));
-    let destination_path = copy_to_target(&filename, verbose, target_options)?;
-    let command_string = format!("dm add-driver:{}", destination_path);
-    if verbose {
-        println!("running {}", command_string);
+/// Returns the value of a flag given in whole seconds.
+fn seconds_value(matches: &ArgMatches, name: &str) -> Result<Option<Duration>, Error> {
+    match matches.value_of(name) {
+        Some(value) => {
+            let seconds = value.parse::<u64>().context(format!("invalid --{} '{}'", name, value))?;
+            Ok(Some(Duration::from_secs(seconds)))
+        }
+        None => Ok(None),
     }
-    ssh(verbose, target_options, &command_string)?;
-    Ok(())
 }
 
-/// Runs the cargo tool configured to target Fuchsia. When used as a library,
-/// the runner options must contain the path to fargo or some other program
-/// that implements the `run-on-target` subcommand in a way compatible with
-/// fargo.
-///
-/// # Examples
-///
-/// ```
-/// use fargo::{run_cargo, TargetOptions};
-///
-/// let target_options = TargetOptions::new(true, None);
-/// run_cargo(false, true, false, &["--help"], &target_options, None, None);
-///
-/// ```
-pub fn run_cargo(
-    verbose: bool,
-    release: bool,
-    set_root_view: bool,
-    args: &[&str],
-    target_options: &TargetOptions,
-    runner: Option<PathBuf>,
-    additional_target_args: Option<&str>,
-) -> Result<(), Error> {
-    let set_root_view_arg = format!("--{}", SET_ROOT_VIEW);
-    let mut target_args = vec!["--target", "x86_64-unknown-fuchsia"];
+fn retries(matches: &ArgMatches) -> Result<usize, Error> {
+    let retries = matches.value_of("retries").unwrap();
+    Ok(retries.parse::<usize>().context(format!("invalid --retries '{}'", retries))?)
+}
 
-    if release {
-        target_args.push("--release");
-    }
+fn test_filter(matches: &ArgMatches) -> Result<TestFilter, Error> {
+    let shard = match (matches.value_of("shard-index"), matches.value_of("shard-count")) {
+        (Some(index), Some(count)) => Some(Shard::new(
+            index.parse::<usize>().context("invalid shard index")?,
+            count.parse::<usize>().context("invalid shard count")?,
+        )?),
+        _ => None,
+    };
+    Ok(TestFilter {
+        filter: matches.value_of("filter").map(String::from),
+        skip: matches.values_of("skip").map_or(vec![], |skip| skip.map(String::from).collect()),
+        exact: matches.is_present("exact"),
+        ignored: matches.is_present("ignored"),
+        shard,
+    })
+}
 
-    if verbose {
-        println!("target_args = {:?}", target_args);
-    }
+/// A cargo flag that `build`, `run` and `test` pass through to cargo.
+struct CargoFlag {
+    flag: &'static str,
+    short: Option<&'static str>,
+    takes_value: bool,
+    /// Whether `run` accepts the flag, which it doesn't for flags selecting more than
+    /// one binary.
+    for_run: bool,
+    help: &'static str,
+}
 
-    let fargo_path = if runner.is_some() {
-        runner.unwrap()
-    } else {
-        fs::canonicalize(std::env::current_exe()?)?
-    };
+static CARGO_FLAGS: &[CargoFlag] = &[
+    CargoFlag {
+        flag: "--package",
+        short: Some("p"),
+        takes_value: true,
+        for_run: true,
+        help: "Package to build",
+    },
+    CargoFlag {
+        flag: "--all",
+        short: None,
+        takes_value: false,
+        for_run: false,
+        help: "Build all packages in the workspace",
+    },
+    CargoFlag {
+        flag: "--exclude",
+        short: None,
+        takes_value: true,
+        for_run: false,
+        help: "Exclude packages from the build",
+    },
+    CargoFlag {
+        flag: "--lib",
+        short: None,
+        takes_value: false,
+        for_run: false,
+        help: "Build only this package's library",
+    },
+    CargoFlag {
+        flag: "--bin",
+        short: None,
+        takes_value: true,
+        for_run: true,
+        help: "Build only the specified binary",
+    },
+    CargoFlag {
+        flag: "--bins",
+        short: None,
+        takes_value: false,
+        for_run: false,
+        help: "Build all binaries",
+    },
+    CargoFlag {
+        flag: "--example",
+        short: None,
+        takes_value: true,
+        for_run: true,
+        help: "Build only the specified example",
+    },
+    CargoFlag {
+        flag: "--examples",
+        short: None,
+        takes_value: false,
+        for_run: false,
+        help: "Build all examples",
+    },
+    CargoFlag {
+        flag: "--test",
+        short: None,
+        takes_value: true,
+        for_run: false,
+        help: "Build only the specified test target",
+    },
+    CargoFlag {
+        flag: "--tests",
+        short: None,
+        takes_value: false,
+        for_run: false,
+        help: "Build all tests",
+    },
+    CargoFlag {
+        flag: "--bench",
+        short: None,
+        takes_value: true,
+        for_run: false,
+        help: "Build only the specified bench target",
+    },
+    CargoFlag {
+        flag: "--benches",
+        short: None,
+        takes_value: false,
+        for_run: false,
+        help: "Build all benches",
+    },
+    CargoFlag {
+        flag: "--all-targets",
+        short: None,
+        takes_value: false,
+        for_run: false,
+        help: "Build all targets",
+    },
+    CargoFlag {
+        flag: "--features",
+        short: None,
+        takes_value: true,
+        for_run: true,
+        help: "Space-separated list of features to activate",
+    },
+    CargoFlag {
+        flag: "--all-features",
+        short: None,
+        takes_value: false,
+        for_run: true,
+        help: "Activate all available features",
+    },
+    CargoFlag {
+        flag: "--no-default-features",
+        short: None,
+        takes_value: false,
+        for_run: true,
+        help: "Do not activate the `default` feature",
+    },
+    CargoFlag {
+        flag: "--jobs",
+        short: Some("j"),
+        takes_value: true,
+        for_run: true,
+        help: "Number of parallel jobs, defaults to # of CPUs",
+    },
+];
+
+fn cargo_flags(for_run: bool) -> Vec<&'static CargoFlag> {
+    CARGO_FLAGS.iter().filter(|cargo_flag| cargo_flag.for_run || !for_run).collect()
+}
 
-    let mut runner_args =
-        vec![
-            fargo_path.to_str().ok_or_else(|| err_msg("unable to convert path to utf8 encoding"))?,
-        ];
+fn cargo_flag_args<'a, 'b>(for_run: bool) -> Vec<Arg<'a, 'b>> {
+    cargo_flags(for_run)
+        .into_iter()
+        .map(|cargo_flag| {
+            let name = &cargo_flag.flag[2..];
+            let mut arg = Arg::with_name(name).long(name).help(cargo_flag.help);
+            if let Some(short) = cargo_flag.short {
+                arg = arg.short(short);
+            }
+            if cargo_flag.takes_value {
+                arg = arg.value_name(name).multiple(true).number_of_values(1);
+            }
+            arg
+        })
+        .collect()
+}
 
-    if verbose {
-        runner_args.push("-v");
+/// Turns the cargo flags given to a fargo subcommand back into cargo arguments.
+fn cargo_flag_params<'a>(matches: &'a ArgMatches, for_run: bool) -> Vec<&'a str> {
+    let mut params = vec![];
+    for cargo_flag in cargo_flags(for_run) {
+        let name = &cargo_flag.flag[2..];
+        if cargo_flag.takes_value {
+            for value in matches.values_of(name).into_iter().flatten() {
+                params.push(cargo_flag.flag);
+                params.push(value);
+            }
+        } else if matches.is_present(name) {
+            params.push(cargo_flag.flag);
+        }
     }
+    params
+}
 
-    if let Some(device_name) = target_options.device_name {
-        runner_args.push("--device-name");
-        runner_args.push(device_name);
+/// Creates an invocation of cargo with `args`, which include the cargo flags given to a
+/// fargo subcommand. The feature flags among them go through the builder, so that they
+/// are combined with the features the invocation selects itself.
+fn cargo_invocation<'a>(target_options: &TargetOptions<'a>, args: &[&str]) -> CargoInvocation<'a> {
+    let mut cargo_args = vec![];
+    let mut features = vec![];
+    let mut no_default_features = false;
+    let mut args = args.iter();
+    while let Some(&arg) = args.next() {
+        match arg {
+            "--features" => features.extend(args.next().into_iter().flat_map(|value| {
+                value.split(|c: char| c == ',' || c.is_whitespace()).filter(|f| !f.is_empty())
+            })),
+            "--no-default-features" => no_default_features = true,
+            // What follows is for the binaries cargo runs.
+            "--" => {
+                cargo_args.push(arg);
+                cargo_args.extend(args.by_ref());
+            }
+            _ => cargo_args.push(arg),
+        }
     }
+    let mut invocation = CargoInvocation::new(target_options, &cargo_args);
+    invocation.features(&features).no_default_features(no_default_features);
+    invocation
+}
 
-    runner_args.push("run-on-target");
-
-    if set_root_view {
-        runner_args.push(&set_root_view_arg);
+/// Whether the subcommand in `matches` runs something on a single device.
+fn uses_one_device(matches: &ArgMatches) -> bool {
+    match matches.subcommand() {
+        ("autotest", _) | ("run", _) | ("load-driver", _) | ("ssh", _) => true,
+        ("test", Some(test_matches)) => {
+            !test_matches.is_present("all-devices") && !test_matches.is_present("devices")
+        }
+        _ => false,
     }
+}
 
-    if let Some(args_for_target) = additional_target_args {
-        runner_args.push(&args_for_target);
-    }
+/// Returns whether the subcommand runs something on a device, and so needs the
+/// settings of the device it targets from the registry.
+fn targets_device(matches: &ArgMatches) -> bool {
+    matches!(
+        matches.subcommand_name(),
+        Some("autotest") | Some("test") | Some("run") | Some("load-driver") | Some("ssh") |
+            Some("run-on-target")
+    )
+}
 
-    let fargo_command = runner_args.join(" ");
+/// Returns whether the subcommand builds the project with cargo, and so depends on the
+/// settings of the project's configuration.
+fn builds_project(matches: &ArgMatches) -> bool {
+    matches!(
+        matches.subcommand_name(),
+        Some("autotest") | Some("build-tests") | Some("test") | Some("build") | Some("run") |
+            Some("package") | Some("load-driver") | Some("cargo") | Some("run-on-target")
+    )
+}
 
-    if verbose {
-        println!("fargo_command: {:?}", fargo_command);
+/// Returns whether the flag `name` is on, either given on the command line or set in the
+/// configuration. `--no-<name>` turns off a value set in the configuration.
+fn flag_value(matches: &ArgMatches, name: &str, config_value: Option<bool>) -> bool {
+    if matches.is_present(name) {
+        true
+    } else if matches.is_present(format!("no-{}", name)) {
+        false
+    } else {
+        config_value.unwrap_or(false)
     }
+}
 
-    let pkg_path = pkg_config_path(target_options)?;
-    let mut cmd = Command::new("cargo");
+fn is_release(matches: &ArgMatches, config: &FargoConfig) -> bool {
+    flag_value(matches, "release", config.release)
+}
 
-    cmd.env("CARGO_TARGET_X86_64_UNKNOWN_FUCHSIA_RUNNER", fargo_command)
-        .env(
-            "CARGO_TARGET_X86_64_UNKNOWN_FUCHSIA_RUSTFLAGS",
-            format!(
-                "-C link-arg=--target=x86_64-unknown-fuchsia \
-                -C link-arg=--sysroot={}",
-                sysroot_path(target_options)?.to_str().unwrap()
-            ),
-        )
-        .env(
-            "CARGO_TARGET_X86_64_UNKNOWN_FUCHSIA_LINKER",
-            clang_linker_path(target_options)?.to_str().unwrap(),
-        )
-        .env("CC", clang_c_compiler_path(target_options)?.to_str().unwrap())
-        .env("CXX", clang_cpp_compiler_path(target_options)?.to_str().unwrap())
-        .env("AR", clang_archiver_path(target_options)?.to_str().unwrap())
-        .env("RANLIB", clang_ranlib_path(target_options)?.to_str().unwrap())
-        .env("PKG_CONFIG_ALL_STATIC", "1")
-        .env("PKG_CONFIG_ALLOW_CROSS", "1")
-        .env("PKG_CONFIG_PATH", "")
-        .env("PKG_CONFIG_LIBDIR", pkg_path)
-        .env("FUCHSIA_GEN_ROOT", target_gen_dir(target_options)?)
-        .args(args)
-        .args(target_args);
+fn is_set_root_view(matches: &ArgMatches, config: &FargoConfig) -> bool {
+    flag_value(matches, SET_ROOT_VIEW, config.set_root_view)
+}
 
-    if verbose {
-        println!("cargo cmd: {:?}", cmd);
+/// Implements `fargo device`, which manages the devices in `~/.fargo/devices.toml`.
+fn manage_devices(matches: &ArgMatches) -> Result<(), Error> {
+    let mut registry = DeviceRegistry::load()?;
+    if let Some(add_matches) = matches.subcommand_matches("add") {
+        let alias = add_matches.value_of("alias").unwrap();
+        let ssh_config = match add_matches.value_of("ssh-config") {
+            Some(ssh_config) => Some(
+                fs::canonicalize(ssh_config).context(format!("unable to find {:?}", ssh_config))?,
+            ),
+            None => None,
+        };
+        let device = RegisteredDevice {
+            nodename: add_matches.value_of("nodename").map(String::from),
+            address: add_matches.value_of("address").map(String::from),
+            ssh_config,
+            variant: add_matches.value_of("variant").map(String::from),
+        };
+        registry.add(alias, device)?;
+        if add_matches.is_present("default") {
+            registry.set_default(alias)?;
+        }
+        return registry.save();
     }
-
-    let cargo_status = cmd.status()?;
-    if !cargo_status.success() {
-        bail!(
-            "cargo exited with status {:?}",
-            cargo_status,
-        );
+    if let Some(remove_matches) = matches.subcommand_matches("remove") {
+        registry.remove(remove_matches.value_of("alias").unwrap())?;
+        return registry.save();
     }
-
+    if let Some(default_matches) = matches.subcommand_matches("default") {
+        return match default_matches.value_of("alias") {
+            Some(alias) => {
+                registry.set_default(alias)?;
+                registry.save()
+            }
+            None => {
+                match registry.default {
+                    Some(ref alias) => println!("{}", alias),
+                    None => println!("no default device"),
+                }
+                Ok(())
+            }
+        };
+    }
+    print!("{}", registry.format_table());
     Ok(())
 }
 
-static CREATE_FACADE: &str = "create-facade";
-static FIDL_PARAM: &str = "fidl_interface_path";
-static FIDL_PARAM_HELP: &str = "gn path and label (i.e. //garnet/public/lib/app/fidl:fidl) \
-for the new facade";
-
-static SET_ROOT_VIEW: &str = "set-root-view";
-
 #[doc(hidden)]
 pub fn run() -> Result<(), Error> {
     let matches = App::new("fargo")
@@ -359,92 +1915,208 @@ pub fn run() -> Result<(), Error> {
         .arg(Arg::with_name("debug-os").long("debug-os").help(
             "Use debug user.bootfs and ssh keys",
         ))
+        .arg(
+            Arg::with_name("no-debug-os")
+                .long("no-debug-os")
+                .conflicts_with("debug-os")
+                .help("Use release user.bootfs and ssh keys even if configured to use debug"),
+        )
+        .arg(Arg::with_name("target-cpu").long("target-cpu").short("T")
+        .value_name("target-cpu")
+        .possible_values(&["x64", "arm64"])
+        .help("Architecture of target device [default: x64]"))
+        .arg(Arg::with_name("native-ssh").long("native-ssh").help(
+            "Use fargo's built-in ssh client instead of the system ssh and scp binaries",
+        ))
+        .arg(
+            Arg::with_name("no-native-ssh")
+                .long("no-native-ssh")
+                .conflicts_with("native-ssh")
+                .help("Use the system ssh and scp binaries even if configured to use native ssh"),
+        )
         .arg(Arg::with_name("device-name").long("device-name").short("N")
         .value_name("device-name").help(
             "Name of device to target, needed if there are multiple devices visible on the network",
         ))
+        .arg(Arg::with_name("device-addr").long("device-addr")
+        .value_name("ip[:port]").help(
+            "Address of device to target, instead of finding it on the network",
+        ))
+        .arg(Arg::with_name("control-path").long("control-path").hidden(true)
+        .takes_value(true).help(
+            "Socket of the ssh control master to share, passed to the runners started by cargo",
+        ))
         .subcommand(
             SubCommand::with_name("autotest")
                 .about("Auto build and test in Fuchsia device or emulator")
-                .arg(Arg::with_name("release").long("release").help(
-                    "Build release",
-                )),
+                .args(&release_args()),
         )
         .subcommand(
             SubCommand::with_name("build-tests")
                 .about("Build tests for Fuchsia device or emulator")
-                .arg(
-                    Arg::with_name("test")
-                        .long("test")
-                        .value_name("test")
-                        .help("Test only the specified test target"),
-                )
-                .arg(Arg::with_name("release").long("release").help(
-                    "Build release",
-                )),
+                .args(&release_args())
+                .args(&cargo_flag_args(false)),
         )
         .subcommand(
             SubCommand::with_name("test")
                 .about("Run unit tests on Fuchsia device or emulator")
-                .arg(Arg::with_name("release").long("release").help(
-                    "Build release",
-                ))
-                .arg(
-                    Arg::with_name("test")
-                        .long("test")
-                        .value_name("test")
-                        .help("Test only the specified test target"),
-                )
+                .args(&release_args())
+                .args(&cargo_flag_args(false))
                 .arg(
                     Arg::with_name("test_args")
                         .long("args")
                         .value_name("args")
                         .help("arguments to pass to the test runner"),
                 )
+                .arg(message_format_arg())
+                .arg(
+                    Arg::with_name("junit")
+                        .long("junit")
+                        .value_name("path")
+                        .help("Write a JUnit XML report of all test results to path"),
+                )
+                .args(&test_filter_args())
+                .arg(timeout_arg())
+                .arg(retries_arg())
+                .arg(
+                    Arg::with_name("run-timeout")
+                        .long("run-timeout")
+                        .value_name("secs")
+                        .help("Stop running test binaries once the test run has taken secs"),
+                )
+                .arg(
+                    Arg::with_name("devices")
+                        .long("devices")
+                        .value_name("names")
+                        .use_delimiter(true)
+                        .help("Spread test binaries over these devices and run them in parallel"),
+                )
+                .arg(
+                    Arg::with_name("all-devices")
+                        .long("all-devices")
+                        .conflicts_with("devices")
+                        .help("Spread test binaries over all devices found on the network"),
+                )
                 .arg(Arg::with_name("test_params").index(1).multiple(true)),
         )
         .subcommand(
             SubCommand::with_name("build")
                 .about("Build binary targeting Fuchsia device or emulator")
-                .arg(Arg::with_name("release").long("release").help(
-                    "Build release",
-                ))
-                .arg(
-                    Arg::with_name("example")
-                        .long("example")
-                        .takes_value(true)
-                        .help("Build a specific example from the examples/ dir."),
-                )
-                .arg(Arg::with_name("examples").long("examples").help(
-                    "Build all examples in the examples/ dir.",
-                )),
+                .args(&release_args())
+                .args(&cargo_flag_args(false)),
         )
         .subcommand(
             SubCommand::with_name("run")
                 .about("Run binary on Fuchsia device or emulator")
-                .arg(Arg::with_name("release").long("release").help(
-                    "Build release",
-                ))
+                .args(&release_args())
                 .arg(Arg::with_name(SET_ROOT_VIEW).long(SET_ROOT_VIEW).help(
                     "Use set_root_view to run binary.",
                 ))
                 .arg(
-                    Arg::with_name("example")
-                        .long("example")
-                        .value_name("example")
-                        .help("Run a specific example from the examples/ dir."),
+                    Arg::with_name("no-set-root-view")
+                        .long("no-set-root-view")
+                        .conflicts_with(SET_ROOT_VIEW)
+                        .help("Run binary directly even if the configuration sets set-root-view"),
+                )
+                .arg(Arg::with_name(PACKAGE_MODE).long(PACKAGE_MODE).help(
+                    "Install the binary as a Fuchsia package and run it as a component",
+                ))
+                .args(&cargo_flag_args(true))
+                .arg(
+                    Arg::with_name("run_params")
+                        .index(1)
+                        .multiple(true)
+                        .help("Arguments to pass to the binary"),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("package")
+                .about("Build Fuchsia package archives of binaries")
+                .args(&release_args())
+                .args(&cargo_flag_args(false)),
+        )
         .subcommand(
             SubCommand::with_name("load-driver")
                 .about("Build driver and load it on Fuchsia device or emulator.")
-                .arg(Arg::with_name("release").long("release").help(
-                    "Build release",
+                .args(&release_args())
+                .arg(
+                    Arg::with_name("package")
+                        .long("package")
+                        .short("p")
+                        .value_name("package")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Load the driver built by the given workspace package"),
+                )
+                .arg(Arg::with_name("all").long("all").help(
+                    "Load the drivers built by all packages in the workspace",
                 ))
         )
         .subcommand(
             SubCommand::with_name("list-devices")
-                .about("List visible Fuchsia devices")
+                .about(
+                    "List visible Fuchsia devices, with a guess at the build each one runs \
+                     from the ssh key it accepts",
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("fmt")
+                        .possible_values(&["table", "json"])
+                        .default_value("table")
+                        .help("Print devices as a table or as a JSON array"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("device")
+                .about("Manage the devices registered in ~/.fargo/devices.toml")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("add")
+                        .about("Register a device under an alias, or change a registered one")
+                        .arg(Arg::with_name("alias").index(1).required(true))
+                        .arg(
+                            Arg::with_name("nodename")
+                                .long("nodename")
+                                .value_name("nodename")
+                                .help("Name the device is found on the network with"),
+                        )
+                        .arg(
+                            Arg::with_name("address")
+                                .long("address")
+                                .value_name("address")
+                                .help("Fixed address, for devices that can't be found"),
+                        )
+                        .arg(
+                            Arg::with_name("ssh-config")
+                                .long("ssh-config")
+                                .value_name("path")
+                                .help("ssh config to use instead of the Fuchsia build's"),
+                        )
+                        .arg(
+                            Arg::with_name("variant")
+                                .long("variant")
+                                .value_name("variant")
+                                .possible_values(&["release", "debug"])
+                                .help("Fuchsia build the device runs"),
+                        )
+                        .arg(
+                            Arg::with_name("default")
+                                .long("default")
+                                .help("Make this the default device"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("remove")
+                        .about("Remove a registered device")
+                        .arg(Arg::with_name("alias").index(1).required(true)),
+                )
+                .subcommand(SubCommand::with_name("list").about("List registered devices"))
+                .subcommand(
+                    SubCommand::with_name("default")
+                        .about("Set the default device, or print it if no alias is given")
+                        .arg(Arg::with_name("alias").index(1)),
+                ),
         )
         .subcommand(
             SubCommand::with_name("start")
@@ -470,9 +2142,14 @@ pub fn run() -> Result<(), Error> {
                 .arg(Arg::with_name("no_net"))
                 .help("Don't set up networking."),
         )
-        .subcommand(SubCommand::with_name("ssh").about(
-            "Open a shell on Fuchsia device or emulator",
-        ))
+        .subcommand(
+            SubCommand::with_name("ssh")
+                .about(
+                    "Open a shell on Fuchsia device or emulator, or run a command there if one \
+                     is given",
+                )
+                .arg(Arg::with_name("ssh_command").index(1).multiple(true)),
+        )
         .subcommand(
             SubCommand::with_name("cargo")
                 .about(
@@ -488,11 +2165,29 @@ pub fn run() -> Result<(), Error> {
                     Arg::with_name("test_args")
                         .long("args")
                         .value_name("args")
-                        .help("arguments to pass to the test runner"),
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("arguments to pass to the test runner, joined with spaces"),
                 )
                 .arg(Arg::with_name(SET_ROOT_VIEW).long(SET_ROOT_VIEW).help(
                     "Use set_root_view to run binary.",
                 ))
+                .arg(message_format_arg())
+                .arg(
+                    Arg::with_name("report")
+                        .long("report")
+                        .value_name("path")
+                        .help("Append test results to the report at path"),
+                )
+                .args(&test_filter_args())
+                .arg(timeout_arg())
+                .arg(retries_arg())
+                .arg(
+                    Arg::with_name("deadline")
+                        .long("deadline")
+                        .value_name("secs")
+                        .help("Seconds since the Unix epoch at which the test run times out"),
+                )
                 .arg(Arg::with_name("run_on_target_params").index(1).multiple(
                     true,
                 ))
@@ -524,8 +2219,69 @@ pub fn run() -> Result<(), Error> {
         .get_matches();
 
     let verbose = matches.is_present("verbose");
-    let target_options =
-        TargetOptions::new(!matches.is_present("debug-os"), matches.value_of("device-name"));
+
+    // Managing the registry doesn't need a Fuchsia tree or the configuration.
+    if let Some(device_matches) = matches.subcommand_matches("device") {
+        return manage_devices(device_matches);
+    }
+
+    let config = match FargoConfig::load() {
+        Ok(config) => config,
+        // Commands that don't build the project only take defaults for the global flags
+        // from the configuration, so a broken one doesn't keep them from working.
+        Err(error) if !builds_project(&matches) => {
+            let causes: Vec<String> = error.iter_chain().map(ToString::to_string).collect();
+            eprintln!("warning: ignoring the fargo configuration: {}", causes.join(", "));
+            FargoConfig::default()
+        }
+        Err(error) => return Err(error.context("loading fargo configuration failed").into()),
+    };
+    if verbose {
+        println!("config = {:#?}", config);
+    }
+
+    // Only commands that run something on a device use the registered devices.
+    let registry = if targets_device(&matches) {
+        DeviceRegistry::load()?
+    } else {
+        DeviceRegistry::default()
+    };
+    let debug_os = flag_value(&matches, "debug-os", config.debug_os);
+    let mut target_options = TargetOptions::new(
+        !debug_os,
+        matches.value_of("target-cpu").or(config.target_cpu.as_deref()).unwrap_or("x64"),
+        matches.value_of("device-name").or(config.device_name.as_deref()),
+    );
+    target_options.native_ssh = flag_value(&matches, "native-ssh", config.native_ssh);
+    let registered = target_options.resolve_device(&registry);
+    // An explicit address wins over the one of a registered device. The configured
+    // address only applies when neither a device name nor the registry chose a device.
+    let config_device_addr = if registered || matches.is_present("device-name") {
+        None
+    } else {
+        config.device_addr.as_deref()
+    };
+    if let Some(device_address) = matches.value_of("device-addr").or(config_device_addr) {
+        parse_device_address(device_address)?;
+        target_options.device_address = Some(device_address);
+    }
+    target_options.control_path = matches.value_of("control-path").map(Path::new);
+    // --debug-os overrides the variant of a registered device.
+    if debug_os {
+        target_options.release_os = false;
+    }
+    // Commands that need a device pick one up front, so that the run-on-target
+    // processes cargo starts all use the same one.
+    let selected_device = if target_options.device_name.is_none() &&
+        target_options.device_address.is_none() && uses_one_device(&matches)
+    {
+        select_device(verbose)?
+    } else {
+        None
+    };
+    if let Some(ref nodename) = selected_device {
+        target_options.device_name = Some(nodename);
+    }
 
     if verbose {
         println!("target_options = {:#?}", target_options);
@@ -537,74 +2293,152 @@ pub fn run() -> Result<(), Error> {
     }
 
     if let Some(autotest_matches) = matches.subcommand_matches("autotest") {
-        return autotest(verbose, autotest_matches.is_present("release"), &target_options);
+        return autotest(
+            verbose,
+            is_release(autotest_matches, &config),
+            &target_options,
+            config.runner_path(),
+        );
     }
 
     if let Some(test_matches) = matches.subcommand_matches("test") {
-        let test_params =
-            test_matches.values_of("test_params").map(|x| x.collect()).unwrap_or_else(|| vec![]);
-        let test_target = test_matches.value_of("test").unwrap_or("");
-        let test_args = test_matches.value_of("test_args");
+        let cargo_params = cargo_flag_params(test_matches, false);
+        let positional_params: Vec<&str> =
+            test_matches.values_of("test_params").map(|x| x.collect()).unwrap_or_default();
+        let test_args = test_matches.value_of("test_args").or(config.test_args.as_deref());
+
+        let timeouts = TestTimeouts::new(
+            seconds_value(test_matches, "timeout")?,
+            seconds_value(test_matches, "run-timeout")?,
+        );
+        let retries = retries(test_matches)?;
+
+        let device_names = if test_matches.is_present("all-devices") {
+            Some(discovered_device_names(verbose)?)
+        } else {
+            test_matches
+                .values_of("devices")
+                .map(|names| names.map(String::from).collect::<Vec<_>>())
+        };
+        let test_options = TestOptions {
+            test_params: positional_params,
+            test_args,
+            message_format: test_matches.value_of(MESSAGE_FORMAT).unwrap().parse()?,
+            junit_path: test_matches.value_of("junit").map(Path::new),
+            filter: test_filter(test_matches)?,
+            timeouts,
+            retries,
+        };
+        if let Some(device_names) = device_names {
+            return run_tests_on_devices(
+                verbose,
+                is_release(test_matches, &config),
+                &target_options,
+                config.runner_path(),
+                &cargo_params,
+                &test_options,
+                &device_names,
+            );
+        }
+
         return run_tests(
             verbose,
-            test_matches.is_present("release"),
+            is_release(test_matches, &config),
             false,
             &target_options,
-            test_target,
-            &test_params,
-            test_args,
+            config.runner_path(),
+            &cargo_params,
+            &test_options,
         );
     }
 
     if let Some(build_matches) = matches.subcommand_matches("build") {
-
-        let mut params = vec![];
-        if let Some(example) = build_matches.value_of("example") {
-            params.push("--example");
-            params.push(example);
-        }
-
-        if build_matches.is_present("examples") {
-            params.push("--examples");
-        }
-
-        build_binary(verbose, build_matches.is_present("release"), &target_options, &params)?;
+        let params = cargo_flag_params(build_matches, false);
+        build_binary(
+            verbose,
+            is_release(build_matches, &config),
+            &target_options,
+            config.runner_path(),
+            &params,
+        )?;
         return Ok(());
     }
 
+    let resources = config.resources.clone().unwrap_or_default();
+
     if let Some(run_matches) = matches.subcommand_matches("run") {
-        let mut params = vec![];
-        if let Some(example) = run_matches.value_of("example") {
-            params.push("--example");
-            params.push(example);
+        let mut params = cargo_flag_params(run_matches, true);
+        if let Some(run_params) = run_matches.values_of("run_params") {
+            params.push("--");
+            params.extend(run_params);
+        }
+        let exit_code = if run_matches.is_present(PACKAGE_MODE) {
+            run_package(
+                verbose,
+                is_release(run_matches, &config),
+                is_set_root_view(run_matches, &config),
+                &target_options,
+                config.runner_path(),
+                &params,
+                &resources,
+            )?
+        } else {
+            run_binary(
+                verbose,
+                is_release(run_matches, &config),
+                is_set_root_view(run_matches, &config),
+                &target_options,
+                config.runner_path(),
+                &params,
+            )?
+        };
+        if exit_code != 0 {
+            process::exit(exit_code);
         }
+        return Ok(());
+    }
 
-        return run_binary(
+    if let Some(package_matches) = matches.subcommand_matches("package") {
+        let params = cargo_flag_params(package_matches, false);
+        build_packages(
             verbose,
-            run_matches.is_present("release"),
-            run_matches.is_present(SET_ROOT_VIEW),
+            is_release(package_matches, &config),
             &target_options,
+            config.runner_path(),
             &params,
-        );
+            &resources,
+        )?;
+        return Ok(());
     }
 
     if let Some(load_driver_matches) = matches.subcommand_matches("load-driver") {
-        return load_driver(verbose, load_driver_matches.is_present("release"), &target_options);
+        let package_names: Vec<&str> =
+            load_driver_matches.values_of("package").map(|x| x.collect()).unwrap_or_default();
+        return load_driver(
+            verbose,
+            is_release(load_driver_matches, &config),
+            &target_options,
+            config.runner_path(),
+            &package_names,
+            load_driver_matches.is_present("all"),
+        );
     }
 
     if let Some(build_test_matches) = matches.subcommand_matches("build-tests") {
-        let test_target = build_test_matches.value_of("test").unwrap_or("");
+        let params = cargo_flag_params(build_test_matches, false);
         build_tests(
             verbose,
-            build_test_matches.is_present("release"),
+            is_release(build_test_matches, &config),
             &target_options,
-            test_target,
+            config.runner_path(),
+            &params,
         )?;
         return Ok(());
     }
 
-    if matches.subcommand_matches("list-devices").is_some() {
-        return netls(verbose, &target_options);
+    if let Some(list_devices_matches) = matches.subcommand_matches("list-devices") {
+        let json = list_devices_matches.value_of("format") == Some("json");
+        return list_devices(verbose, &target_options, json);
     }
 
     if let Some(start_matches) = matches.subcommand_matches("start") {
@@ -623,7 +2457,7 @@ pub fn run() -> Result<(), Error> {
     }
 
     if matches.subcommand_matches("stop").is_some() {
-        return stop_emulator();
+        return stop_emulator(&target_options);
     }
 
     if matches.subcommand_matches("enable-networking").is_some() {
@@ -631,7 +2465,7 @@ pub fn run() -> Result<(), Error> {
     }
 
     if let Some(restart_matches) = matches.subcommand_matches("restart") {
-        stop_emulator()?;
+        stop_emulator(&target_options)?;
 
         return start_emulator(
             restart_matches.is_present("graphics"),
@@ -640,8 +2474,16 @@ pub fn run() -> Result<(), Error> {
         );
     }
 
-    if matches.subcommand_matches("ssh").is_some() {
-        return ssh(verbose, &target_options, "");
+    if let Some(ssh_matches) = matches.subcommand_matches("ssh") {
+        let ssh_command = ssh_matches
+            .values_of("ssh_command")
+            .map(|x| x.collect::<Vec<_>>().join(" "))
+            .unwrap_or_default();
+        let exit_code = ssh(verbose, &target_options, &ssh_command)?;
+        if exit_code != 0 {
+            process::exit(exit_code);
+        }
+        return Ok(());
     }
 
     if let Some(cargo_matches) = matches.subcommand_matches("cargo") {
@@ -655,16 +2497,35 @@ pub fn run() -> Result<(), Error> {
             .values_of("run_on_target_params")
             .map(|x| x.collect())
             .unwrap_or_else(|| vec![]);
-        let test_args = run_on_target_matches.value_of("test_args");
+        let test_args = run_on_target_matches
+            .values_of("test_args")
+            .map(|values| values.collect::<Vec<_>>().join(" "));
         let (program, args) = run_params.split_first().unwrap();
-        return run_program_on_target(
+        let test_options = TestOptions {
+            test_params: args.to_vec(),
+            test_args: test_args.as_deref(),
+            message_format: run_on_target_matches.value_of(MESSAGE_FORMAT).unwrap().parse()?,
+            junit_path: None,
+            filter: test_filter(run_on_target_matches)?,
+            timeouts: TestTimeouts {
+                binary: seconds_value(run_on_target_matches, "timeout")?,
+                deadline: seconds_value(run_on_target_matches, "deadline")?
+                    .map(|since_epoch| UNIX_EPOCH + since_epoch),
+            },
+            retries: retries(run_on_target_matches)?,
+        };
+        let exit_code = run_program_on_target(
             program,
             verbose,
             &target_options,
             run_on_target_matches.is_present(SET_ROOT_VIEW),
-            args,
-            test_args,
-        );
+            run_on_target_matches.value_of("report").map(Path::new),
+            &test_options,
+        )?;
+        if exit_code != 0 {
+            process::exit(exit_code);
+        }
+        return Ok(());
     }
 
     if let Some(pkg_matches) = matches.subcommand_matches("pkg-config") {
@@ -672,7 +2533,7 @@ pub fn run() -> Result<(), Error> {
             pkg_matches.values_of("pkgconfig_param").map(|x| x.collect()).unwrap_or_else(|| vec![]);
         let exit_code = run_pkg_config(verbose, &pkg_params, &target_options)?;
         if exit_code != 0 {
-            ::std::process::exit(exit_code);
+            process::exit(exit_code);
         }
         return Ok(());
     }
@@ -698,3 +2559,214 @@ pub fn run() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use device::Transport;
+    use error::is_transport_failure;
+    use libtest::MessageFormat;
+    use sdk::{TargetOptions, cargo_out_dir};
+    use std::collections::VecDeque;
+    use std::path::{Path, PathBuf};
+    use std::sync::Mutex;
+    use test_util::{FakeDevice, FakeRun};
+    use {CargoInvocation, TestOptions, cargo_invocation, run_device_tests,
+         run_test_binary_with_retries, test_args_runner_args};
+
+    #[test]
+    fn test_cargo_invocation() {
+        let mut target_options = TargetOptions::new(true, "x64", Some("ivy-donut-grew-stoop"));
+        let control_path = PathBuf::from("/tmp/ssh-control");
+        target_options.control_path = Some(&control_path);
+        let mut invocation = CargoInvocation::new(&target_options, &["test", "--all"]);
+        invocation
+            .release(true)
+            .features(&["fidl", "tls"])
+            .no_default_features(true)
+            .target_cpu("arm64")
+            .set_root_view(true)
+            .runner(PathBuf::from("/usr/bin/fargo"))
+            .target_arg("--message-format=json");
+        assert_eq!(
+            invocation.cargo_args(),
+            vec![
+                "test",
+                "--all",
+                "--target",
+                "aarch64-unknown-fuchsia",
+                "--release",
+                "--features",
+                "fidl,tls",
+                "--no-default-features",
+            ]
+        );
+        assert_eq!(
+            invocation.runner_command().unwrap(),
+            "/usr/bin/fargo --target-cpu arm64 --no-native-ssh --no-debug-os \
+             --device-name ivy-donut-grew-stoop --control-path /tmp/ssh-control run-on-target \
+             --set-root-view --message-format=json"
+        );
+
+        invocation.target_arg("--report=/tmp/test results.jsonl");
+        assert!(invocation.runner_command().is_err());
+
+        let debug_invocation = CargoInvocation::new(&target_options, &["build"]);
+        assert_eq!(
+            debug_invocation.cargo_args(),
+            vec!["build", "--target", "x86_64-unknown-fuchsia"]
+        );
+    }
+
+    #[test]
+    fn test_cargo_invocation_profile() {
+        let target_options = TargetOptions::new(true, "x64", None);
+        let mut invocation = CargoInvocation::new(&target_options, &["build"]);
+        assert_eq!(invocation.profile_name(), None);
+        invocation.release(true);
+        assert_eq!(invocation.profile_name(), Some("release"));
+        invocation.profile("profiling");
+        assert_eq!(invocation.profile_name(), Some("profiling"));
+        assert_eq!(
+            invocation.cargo_args(),
+            vec!["build", "--target", "x86_64-unknown-fuchsia", "--profile", "profiling"]
+        );
+        assert_eq!(
+            cargo_out_dir(Path::new("/work/target"), &target_options, invocation.profile_name()),
+            Path::new("/work/target/x86_64-unknown-fuchsia/profiling")
+        );
+    }
+
+    #[test]
+    fn test_cargo_invocation_features() {
+        let target_options = TargetOptions::new(true, "x64", None);
+        let invocation = cargo_invocation(
+            &target_options,
+            &[
+                "test",
+                "--features",
+                "fidl tls",
+                "--no-default-features",
+                "--features",
+                "json",
+                "--",
+                "--features",
+            ],
+        );
+        assert_eq!(
+            invocation.cargo_args(),
+            vec![
+                "test",
+                "--target",
+                "x86_64-unknown-fuchsia",
+                "--features",
+                "fidl,tls,json",
+                "--no-default-features",
+                "--",
+                "--features",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_test_args_runner_args() {
+        assert_eq!(
+            test_args_runner_args("--nocapture  --test-threads=1"),
+            vec!["--args=--nocapture", "--args=--test-threads=1"]
+        );
+        assert_eq!(test_args_runner_args(""), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_run_device_tests_requeues() {
+        let binaries: VecDeque<PathBuf> =
+            vec![PathBuf::from("/tmp/a"), PathBuf::from("/tmp/b")].into_iter().collect();
+        let binaries = Mutex::new(binaries);
+        let runner = |script: &str| vec!["sh".to_string(), "-c".to_string(), script.to_string()];
+
+        // A device that can't be reached leaves the binary it took to the other devices.
+        let unreachable = run_device_tests(
+            "unreachable",
+            &runner("exit 255"),
+            &[],
+            MessageFormat::Human,
+            &binaries,
+        ).unwrap();
+        assert!(unreachable.is_empty());
+        assert_eq!(binaries.lock().unwrap().len(), 2);
+
+        let failed = run_device_tests(
+            "reachable",
+            &runner("test \"$0\" = /tmp/a"),
+            &[],
+            MessageFormat::Human,
+            &binaries,
+        ).unwrap();
+        assert_eq!(failed, vec!["b".to_string()]);
+        assert_eq!(*binaries.lock().unwrap(), VecDeque::new());
+    }
+
+    #[test]
+    fn test_run_test_binary_with_retries() {
+        let device = FakeDevice::default();
+        let fake_transport = || -> Box<dyn Transport> { Box::new(device.clone()) };
+        let mut connections = 0;
+        let test_options = TestOptions {
+            retries: 2,
+            ..TestOptions::default()
+        };
+
+        device.push_run(FakeRun::ConnectionLost(String::from("running 2 tests")));
+        device.push_run(FakeRun::Exit(
+            String::from(
+                "running 2 tests\ntest tests::a ... ok\ntest tests::b ... FAILED\n\n\
+                 test result: FAILED. 1 passed; 1 failed; 0 ignored; 0 measured; 0 filtered out",
+            ),
+            101,
+        ));
+        device.push_run(FakeRun::Exit(String::from("tests::a: test\ntests::b: test"), 0));
+        device.push_run(FakeRun::Exit(
+            String::from(
+                "running 1 test\ntest tests::b ... ok\n\n\
+                 test result: ok. 1 passed; 0 failed; 0 ignored; 0 measured; 1 filtered out",
+            ),
+            0,
+        ));
+        let (exit_code, test_run) = run_test_binary_with_retries(
+            &mut fake_transport(),
+            &mut || {
+                connections += 1;
+                Ok(fake_transport())
+            },
+            "fargo_test-1234",
+            "/tmp/fargo_test-1234",
+            false,
+            &[],
+            &test_options,
+        ).unwrap();
+        assert_eq!(exit_code, 0);
+        assert_eq!(connections, 1);
+        assert_eq!(test_run.summary.transport_failures, 1);
+        assert_eq!(test_run.passed_on_retry(), vec!["tests::b"]);
+        assert_eq!(
+            device.commands()[2..],
+            [
+                "/tmp/fargo_test-1234 --list",
+                "/tmp/fargo_test-1234 --exact --skip tests::a",
+            ]
+        );
+
+        // Only a lost connection is worth running the binary again for.
+        device.push_run(FakeRun::Failure);
+        let error = run_test_binary_with_retries(
+            &mut fake_transport(),
+            &mut || panic!("reconnected after a failure of the binary"),
+            "fargo_test-1234",
+            "/tmp/fargo_test-1234",
+            false,
+            &[],
+            &test_options,
+        ).unwrap_err();
+        assert!(!is_transport_failure(&error));
+        assert!(device.all_run());
+    }
+}
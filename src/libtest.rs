@@ -0,0 +1,614 @@
+// Copyright 2018 The Fuchsia Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use failure::{Error, ResultExt};
+use serde_json;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::cmp;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use utils::duration_as_milliseconds;
+
+/// How `run-on-target` reports the results of test binaries.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum MessageFormat {
+    /// Pass the output of the test binary through unchanged.
+    #[default]
+    Human,
+    /// Parse the libtest output and print one JSON object per test, plus a summary.
+    Json,
+}
+
+impl FromStr for MessageFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<MessageFormat, Error> {
+        match s {
+            "human" => Ok(MessageFormat::Human),
+            "json" => Ok(MessageFormat::Json),
+            _ => bail!("unknown message format '{}'", s),
+        }
+    }
+}
+
+/// One of `count` deterministic partitions of the tests of a binary.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Shard {
+    pub index: usize,
+    pub count: usize,
+}
+
+impl Shard {
+    pub fn new(index: usize, count: usize) -> Result<Shard, Error> {
+        if count == 0 || index >= count {
+            bail!("shard index {} is not less than the shard count {}", index, count);
+        }
+        Ok(Shard { index, count })
+    }
+
+    /// Returns the tests of this shard, taking every `count`th test in name order so
+    /// that every shard gets a similar number of tests.
+    pub fn select(&self, tests: &[String]) -> Vec<String> {
+        let mut tests = tests.to_vec();
+        tests.sort();
+        tests.into_iter().skip(self.index).step_by(self.count).collect()
+    }
+}
+
+/// Selects which tests of each test binary run.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TestFilter {
+    pub filter: Option<String>,
+    pub skip: Vec<String>,
+    pub exact: bool,
+    pub ignored: bool,
+    pub shard: Option<Shard>,
+}
+
+impl TestFilter {
+    /// The arguments that make a libtest binary apply the filter, apart from sharding.
+    pub fn libtest_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(ref filter) = self.filter {
+            args.push(filter.clone());
+        }
+        for skip in &self.skip {
+            args.push(String::from("--skip"));
+            args.push(skip.clone());
+        }
+        if self.exact {
+            args.push(String::from("--exact"));
+        }
+        if self.ignored {
+            args.push(String::from("--ignored"));
+        }
+        args
+    }
+
+    /// The libtest arguments that run exactly `tests` out of `all_tests`, all the tests
+    /// the binary lists. Older versions of libtest only apply the first test name they
+    /// are given, so the other tests are skipped instead of naming the ones to run.
+    pub fn only_libtest_args(&self, all_tests: &[String], tests: &[String]) -> Vec<String> {
+        let mut args = vec![String::from("--exact")];
+        for test in all_tests.iter().filter(|test| !tests.contains(test)) {
+            args.push(String::from("--skip"));
+            args.push(test.clone());
+        }
+        if self.ignored {
+            args.push(String::from("--ignored"));
+        }
+        args
+    }
+
+    /// The flags that pass the filter on to `run-on-target`.
+    pub fn runner_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(ref filter) = self.filter {
+            args.push(format!("--filter={}", filter));
+        }
+        for skip in &self.skip {
+            args.push(format!("--skip={}", skip));
+        }
+        if self.exact {
+            args.push(String::from("--exact"));
+        }
+        if self.ignored {
+            args.push(String::from("--ignored"));
+        }
+        if let Some(shard) = self.shard {
+            args.push(format!("--shard-index={}", shard.index));
+            args.push(format!("--shard-count={}", shard.count));
+        }
+        args
+    }
+}
+
+/// How long test binaries may run on the device before they are considered hung.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TestTimeouts {
+    /// How long each test binary may run.
+    pub binary: Option<Duration>,
+    /// When the whole test run has to be over. It is a point in time rather than a
+    /// duration so that every runner process cargo starts shares it.
+    pub deadline: Option<SystemTime>,
+}
+
+impl TestTimeouts {
+    /// Timeouts for a test run starting now and lasting at most `run`.
+    pub fn new(binary: Option<Duration>, run: Option<Duration>) -> TestTimeouts {
+        TestTimeouts {
+            binary,
+            deadline: run.map(|run| SystemTime::now() + run),
+        }
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.binary.is_some() || self.deadline.is_some()
+    }
+
+    /// How long the next test binary may run, if it is limited at all. Zero once the
+    /// deadline of the run has passed.
+    pub fn remaining(&self) -> Option<Duration> {
+        let until_deadline = self.deadline.map(|deadline| {
+            deadline.duration_since(SystemTime::now()).unwrap_or_else(|_| Duration::from_secs(0))
+        });
+        match (self.binary, until_deadline) {
+            (Some(binary), Some(until_deadline)) => Some(cmp::min(binary, until_deadline)),
+            (binary, until_deadline) => binary.or(until_deadline),
+        }
+    }
+
+    /// The flags that pass the timeouts on to `run-on-target`.
+    pub fn runner_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(binary) = self.binary {
+            args.push(format!("--timeout={}", binary.as_secs()));
+        }
+        if let Some(deadline) = self.deadline {
+            let since_epoch = deadline.duration_since(UNIX_EPOCH).unwrap_or_default();
+            args.push(format!("--deadline={}", since_epoch.as_secs()));
+        }
+        args
+    }
+}
+
+/// Parses the output of a libtest binary run with `--list`, which names each test
+/// on a line like `tests::noop_test: test`. Benchmarks are left out.
+pub fn parse_test_list(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter(|line| line.ends_with(": test"))
+        .map(|line| line[..line.len() - ": test".len()].to_string())
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TestOutcome {
+    Ok,
+    Failed,
+    Ignored,
+    Measured,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct TestResult {
+    pub name: String,
+    pub binary: String,
+    pub outcome: TestOutcome,
+    /// Time in seconds between this result and the previous one reported by the binary.
+    /// libtest does not report per-test timings, so this is only exact when tests run
+    /// on a single thread.
+    pub duration: f64,
+    pub stdout: Option<String>,
+    /// The device the test ran on, when tests are spread over several devices.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    /// How many times the test was run again after failing before this result.
+    #[serde(default)]
+    pub retries: usize,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct TestSummary {
+    pub binary: String,
+    pub passed: usize,
+    pub failed: usize,
+    pub ignored: usize,
+    pub measured: usize,
+    pub filtered_out: usize,
+    pub duration: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    /// Whether the binary was stopped for running longer than its timeout, in which
+    /// case the counts only cover the tests that finished before.
+    #[serde(default)]
+    pub timed_out: bool,
+    /// How many times the connection to the device failed while running the binary.
+    #[serde(default)]
+    pub transport_failures: usize,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum TestMessage {
+    Test(TestResult),
+    Summary(TestSummary),
+}
+
+impl TestMessage {
+    pub fn to_json(&self) -> Result<String, Error> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    /// Attributes the message to the device it came from.
+    pub fn set_device(&mut self, device: &str) {
+        match *self {
+            TestMessage::Test(ref mut result) => result.device = Some(device.to_string()),
+            TestMessage::Summary(ref mut summary) => summary.device = Some(device.to_string()),
+        }
+    }
+}
+
+/// The results of running a test binary, including those of running its failed
+/// tests again.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TestRun {
+    pub results: Vec<TestResult>,
+    pub summary: TestSummary,
+}
+
+impl TestRun {
+    pub fn failed_tests(&self) -> Vec<String> {
+        self.results
+            .iter()
+            .filter(|result| result.outcome == TestOutcome::Failed)
+            .map(|result| result.name.clone())
+            .collect()
+    }
+
+    /// The tests that failed at first but passed when they were run again.
+    pub fn passed_on_retry(&self) -> Vec<&str> {
+        self.results
+            .iter()
+            .filter(|result| result.outcome == TestOutcome::Ok && result.retries > 0)
+            .map(|result| result.name.as_str())
+            .collect()
+    }
+
+    /// Replaces the results of the failed tests with the ones from running them again
+    /// for the `retry`th time, updating the summary to match.
+    pub fn merge_retry(&mut self, retry_run: TestRun, retry: usize) {
+        for retry_result in retry_run.results {
+            let result = match self.results.iter_mut().find(|result| {
+                result.name == retry_result.name && result.outcome == TestOutcome::Failed
+            }) {
+                Some(result) => result,
+                None => continue,
+            };
+            self.summary.failed = self.summary.failed.saturating_sub(1);
+            match retry_result.outcome {
+                TestOutcome::Ok => self.summary.passed += 1,
+                TestOutcome::Failed => self.summary.failed += 1,
+                TestOutcome::Ignored => self.summary.ignored += 1,
+                TestOutcome::Measured => self.summary.measured += 1,
+            }
+            *result = TestResult {
+                retries: retry,
+                ..retry_result
+            };
+        }
+        self.summary.duration += retry_run.summary.duration;
+        self.summary.timed_out |= retry_run.summary.timed_out;
+    }
+
+    pub fn into_messages(self) -> Vec<TestMessage> {
+        let mut messages: Vec<TestMessage> =
+            self.results.into_iter().map(TestMessage::Test).collect();
+        messages.push(TestMessage::Summary(self.summary));
+        messages
+    }
+}
+
+/// Appends messages, one JSON object per line, to the report file shared by all the
+/// `run-on-target` processes that cargo spawns for a single `fargo test` invocation.
+pub fn append_to_report(report_path: &Path, messages: &[TestMessage]) -> Result<(), Error> {
+    let mut contents = String::new();
+    for message in messages {
+        contents.push_str(&message.to_json()?);
+        contents.push('\n');
+    }
+    let mut report_file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(report_path)
+        .context(format!("unable to open test report {:?}", report_path))?;
+    report_file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+pub fn read_report(report_path: &Path) -> Result<Vec<TestMessage>, Error> {
+    if !report_path.exists() {
+        return Ok(vec![]);
+    }
+    let report_file =
+        File::open(report_path).context(format!("unable to open test report {:?}", report_path))?;
+    let mut messages = vec![];
+    for line in BufReader::new(report_file).lines() {
+        let line = line?;
+        if !line.is_empty() {
+            messages.push(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(messages)
+}
+
+fn seconds(duration: &Duration) -> f64 {
+    duration_as_milliseconds(duration) as f64 / 1000.0
+}
+
+/// Incrementally parses the human readable output of a libtest test binary.
+#[derive(Debug)]
+pub struct LibtestParser {
+    binary: String,
+    results: Vec<TestResult>,
+    summary: Option<TestSummary>,
+    last_result_time: Duration,
+    current_stdout: Option<(String, String)>,
+}
+
+impl LibtestParser {
+    pub fn new(binary: &str) -> LibtestParser {
+        LibtestParser {
+            binary: binary.to_string(),
+            results: vec![],
+            summary: None,
+            last_result_time: Duration::from_secs(0),
+            current_stdout: None,
+        }
+    }
+
+    /// Feeds one line of output, `elapsed` being the time since the binary started.
+    pub fn parse_line(&mut self, line: &str, elapsed: Duration) {
+        if let Some((name, mut stdout)) = self.current_stdout.take() {
+            if line.starts_with("---- ") || line == "failures:" {
+                self.attach_stdout(&name, stdout);
+            } else {
+                stdout.push_str(line);
+                stdout.push('\n');
+                self.current_stdout = Some((name, stdout));
+                return;
+            }
+        }
+
+        if line.starts_with("test result: ") {
+            self.summary = Some(self.parse_summary(line, elapsed));
+        } else if line.starts_with("test ") {
+            self.parse_result(line, elapsed);
+        } else if line.starts_with("---- ") && line.ends_with(" stdout ----") {
+            let name = &line["---- ".len()..line.len() - " stdout ----".len()];
+            self.current_stdout = Some((name.to_string(), String::new()));
+        }
+    }
+
+    fn parse_result(&mut self, line: &str, elapsed: Duration) {
+        let mut parts = line["test ".len()..].rsplitn(2, " ... ");
+        let status = parts.next().unwrap_or("");
+        let name = match parts.next() {
+            Some(name) => name,
+            None => return,
+        };
+        let outcome = if status == "ok" {
+            TestOutcome::Ok
+        } else if status == "FAILED" {
+            TestOutcome::Failed
+        } else if status == "ignored" {
+            TestOutcome::Ignored
+        } else if status.starts_with("bench:") {
+            TestOutcome::Measured
+        } else {
+            return;
+        };
+        let duration = elapsed.checked_sub(self.last_result_time).unwrap_or_default();
+        self.last_result_time = elapsed;
+        self.results.push(TestResult {
+            name: name.to_string(),
+            binary: self.binary.clone(),
+            outcome,
+            duration: seconds(&duration),
+            stdout: None,
+            device: None,
+            retries: 0,
+        });
+    }
+
+    fn parse_summary(&self, line: &str, elapsed: Duration) -> TestSummary {
+        let mut summary = TestSummary {
+            binary: self.binary.clone(),
+            duration: seconds(&elapsed),
+            ..TestSummary::default()
+        };
+        let counts = line.split_once(". ").map_or("", |(_, counts)| counts);
+        for count in counts.split("; ") {
+            let mut words = count.split_whitespace();
+            let value = words.next().and_then(|v| v.parse().ok()).unwrap_or(0);
+            match words.collect::<Vec<_>>().join(" ").as_str() {
+                "passed" => summary.passed = value,
+                "failed" => summary.failed = value,
+                "ignored" => summary.ignored = value,
+                "measured" => summary.measured = value,
+                "filtered out" => summary.filtered_out = value,
+                _ => (),
+            }
+        }
+        summary
+    }
+
+    fn attach_stdout(&mut self, name: &str, stdout: String) {
+        if let Some(result) = self.results.iter_mut().find(|r| r.name == name) {
+            result.stdout = Some(stdout);
+        }
+    }
+
+    /// Returns the parsed results along with the summary line, if one was seen. A missing
+    /// summary usually means the binary crashed or the connection to the device was lost.
+    pub fn finish(mut self, elapsed: Duration) -> (Vec<TestResult>, TestSummary) {
+        if let Some((name, stdout)) = self.current_stdout.take() {
+            self.attach_stdout(&name, stdout);
+        }
+        let summary = match self.summary.take() {
+            Some(summary) => summary,
+            None => {
+                let count = |outcome| self.results.iter().filter(|r| r.outcome == outcome).count();
+                TestSummary {
+                    binary: self.binary.clone(),
+                    passed: count(TestOutcome::Ok),
+                    failed: count(TestOutcome::Failed),
+                    ignored: count(TestOutcome::Ignored),
+                    measured: count(TestOutcome::Measured),
+                    filtered_out: 0,
+                    duration: seconds(&elapsed),
+                    device: None,
+                    timed_out: false,
+                    transport_failures: 0,
+                }
+            }
+        };
+        (self.results, summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libtest::{LibtestParser, Shard, TestFilter, TestOutcome, TestRun, TestTimeouts,
+                  parse_test_list};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    static OUTPUT: &str = "
+running 3 tests
+test tests::noop_test ... ok
+test tests::bad_test ... FAILED
+test tests::slow_test ... ignored
+
+failures:
+
+---- tests::bad_test stdout ----
+thread 'tests::bad_test' panicked at 'assertion failed', src/main.rs:30:8
+note: Run with `RUST_BACKTRACE=1` for a backtrace.
+
+
+failures:
+    tests::bad_test
+
+test result: FAILED. 1 passed; 1 failed; 1 ignored; 0 measured; 2 filtered out
+";
+
+    #[test]
+    fn test_parse_libtest_output() {
+        let mut parser = LibtestParser::new("fargo_test-1234");
+        for (i, line) in OUTPUT.lines().enumerate() {
+            parser.parse_line(line, Duration::from_millis(i as u64 * 100));
+        }
+        let (results, summary) = parser.finish(Duration::from_secs(2));
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].name, "tests::noop_test");
+        assert_eq!(results[0].outcome, TestOutcome::Ok);
+        assert_eq!(results[0].binary, "fargo_test-1234");
+        assert_eq!(results[1].outcome, TestOutcome::Failed);
+        assert_eq!(results[1].duration, 0.1);
+        assert!(results[1].stdout.as_ref().unwrap().contains("assertion failed"));
+        assert_eq!(results[2].outcome, TestOutcome::Ignored);
+        assert_eq!(results[2].stdout, None);
+        assert_eq!(summary.passed, 1);
+        assert_eq!(summary.failed, 1);
+        assert_eq!(summary.ignored, 1);
+        assert_eq!(summary.filtered_out, 2);
+        assert_eq!(summary.duration, 1.6);
+    }
+
+    #[test]
+    fn test_summary_without_result_line() {
+        let mut parser = LibtestParser::new("crashed");
+        parser.parse_line("test tests::noop_test ... ok", Duration::from_millis(10));
+        let (results, summary) = parser.finish(Duration::from_millis(500));
+        assert_eq!(results.len(), 1);
+        assert_eq!(summary.passed, 1);
+        assert_eq!(summary.duration, 0.5);
+    }
+
+    #[test]
+    fn test_shards() {
+        let tests = parse_test_list(
+            "tests::c: test\ntests::a: test\nbenches::b: benchmark\ntests::b: test\n\n\
+             3 tests, 1 benchmarks\n",
+        );
+        assert_eq!(tests, vec!["tests::c", "tests::a", "tests::b"]);
+        assert_eq!(Shard::new(0, 2).unwrap().select(&tests), vec!["tests::a", "tests::c"]);
+        assert_eq!(Shard::new(1, 2).unwrap().select(&tests), vec!["tests::b"]);
+        assert!(Shard::new(2, 2).is_err());
+
+        let filter = TestFilter {
+            filter: Some(String::from("tests::")),
+            skip: vec![String::from("slow")],
+            ignored: true,
+            ..TestFilter::default()
+        };
+        assert_eq!(filter.libtest_args(), vec!["tests::", "--skip", "slow", "--ignored"]);
+        assert_eq!(
+            filter.only_libtest_args(&tests, &tests[..1]),
+            vec!["--exact", "--skip", "tests::a", "--skip", "tests::b", "--ignored"]
+        );
+    }
+
+    fn parse_run(output: &str) -> TestRun {
+        let mut parser = LibtestParser::new("fargo_test-1234");
+        for line in output.lines() {
+            parser.parse_line(line, Duration::from_millis(0));
+        }
+        let (results, summary) = parser.finish(Duration::from_secs(1));
+        TestRun { results, summary }
+    }
+
+    #[test]
+    fn test_merge_retry() {
+        let mut test_run = parse_run(OUTPUT);
+        assert_eq!(test_run.failed_tests(), vec!["tests::bad_test"]);
+
+        test_run.merge_retry(parse_run("test tests::bad_test ... FAILED"), 1);
+        assert_eq!(test_run.summary.failed, 1);
+        assert!(test_run.passed_on_retry().is_empty());
+
+        test_run.merge_retry(parse_run("test tests::bad_test ... ok"), 2);
+        assert_eq!(test_run.summary.passed, 2);
+        assert_eq!(test_run.summary.failed, 0);
+        assert_eq!(test_run.summary.duration, 2.0);
+        assert_eq!(test_run.results[1].retries, 2);
+        assert_eq!(test_run.passed_on_retry(), vec!["tests::bad_test"]);
+        assert!(test_run.failed_tests().is_empty());
+    }
+
+    #[test]
+    fn test_timeouts() {
+        assert_eq!(TestTimeouts::default().remaining(), None);
+
+        let timeouts = TestTimeouts::new(Some(Duration::from_secs(60)), None);
+        assert_eq!(timeouts.remaining(), Some(Duration::from_secs(60)));
+        assert_eq!(timeouts.runner_args(), vec!["--timeout=60"]);
+
+        let timeouts = TestTimeouts {
+            binary: Some(Duration::from_secs(60)),
+            deadline: Some(SystemTime::now() + Duration::from_secs(3600)),
+        };
+        assert_eq!(timeouts.remaining(), Some(Duration::from_secs(60)));
+
+        let timeouts = TestTimeouts {
+            binary: None,
+            deadline: Some(UNIX_EPOCH + Duration::from_secs(1000)),
+        };
+        assert_eq!(timeouts.remaining(), Some(Duration::from_secs(0)));
+        assert_eq!(timeouts.runner_args(), vec!["--deadline=1000"]);
+    }
+}
@@ -0,0 +1,60 @@
+// Copyright 2018 The Fuchsia Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use sha2::{Digest, Sha256};
+
+const BLOCK_SIZE: usize = 8192;
+
+fn hash_block(block: &[u8], level: usize, offset: usize) -> Vec<u8> {
+    // Every block is hashed along with its position in the tree and its length.
+    let mut identity = vec![];
+    identity.write_u64::<LittleEndian>(offset as u64 | (level as u64) << 56).unwrap();
+    identity.write_u32::<LittleEndian>(block.len() as u32).unwrap();
+
+    let mut hasher = Sha256::default();
+    hasher.input(&identity);
+    hasher.input(block);
+    if !block.is_empty() {
+        hasher.input(&vec![0; BLOCK_SIZE - block.len()]);
+    }
+    hasher.result().to_vec()
+}
+
+fn hash_level(data: &[u8], level: usize) -> Vec<Vec<u8>> {
+    if data.is_empty() {
+        return vec![hash_block(data, level, 0)];
+    }
+    data.chunks(BLOCK_SIZE)
+        .enumerate()
+        .map(|(index, block)| hash_block(block, level, index * BLOCK_SIZE))
+        .collect()
+}
+
+/// Returns the Fuchsia merkle root of `data`, the name blobs are stored and
+/// referred to by on the device, as a hex string.
+pub fn merkle_root(data: &[u8]) -> String {
+    let mut level = 0;
+    let mut hashes = hash_level(data, level);
+    while hashes.len() > 1 {
+        level += 1;
+        hashes = hash_level(&hashes.concat(), level);
+    }
+    hashes[0].iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use merkle::merkle_root;
+
+    #[test]
+    fn test_merkle_root() {
+        assert_eq!(
+            merkle_root(&[]),
+            "15ec7bf0b50732b49f8228e07d24365338f9e3ab994b00af08e5a3bffe55fd8b"
+        );
+        // Data of more than one block needs a second level in the tree.
+        assert_ne!(merkle_root(&[0xff; 8192]), merkle_root(&[0xff; 8193]));
+    }
+}
@@ -0,0 +1,308 @@
+// Copyright 2018 The Fuchsia Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use failure::{Error, ResultExt};
+use serde_json;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// A build target of a package, as reported by `cargo metadata`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Target {
+    pub name: String,
+    pub kind: Vec<String>,
+    #[serde(default)]
+    pub crate_types: Vec<String>,
+}
+
+impl Target {
+    pub fn is_cdylib(&self) -> bool {
+        self.crate_types.iter().any(|crate_type| crate_type == "cdylib")
+    }
+
+    /// The file name cargo gives the shared library built from this target.
+    pub fn cdylib_file_name(&self) -> String {
+        format!("lib{}.so", self.name.replace("-", "_"))
+    }
+}
+
+/// A file produced by a build, as reported in a `compiler-artifact` message when cargo
+/// runs with `--message-format=json`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct CargoArtifact {
+    pub package_id: String,
+    pub target: Target,
+    #[serde(default)]
+    pub profile: ArtifactProfile,
+    pub filenames: Vec<PathBuf>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct ArtifactProfile {
+    /// Whether the artifact was built as a libtest test harness.
+    #[serde(default)]
+    pub test: bool,
+}
+
+impl CargoArtifact {
+    /// Returns the shared library of a `cdylib` target, if this is one.
+    pub fn cdylib_path(&self) -> Option<&Path> {
+        if !self.target.is_cdylib() {
+            return None;
+        }
+        self.filenames
+            .iter()
+            .find(|filename| filename.extension().is_some_and(|extension| extension == "so"))
+            .map(PathBuf::as_path)
+    }
+
+    /// Returns the executable of a binary or example target, if this is one.
+    pub fn executable_path(&self) -> Option<&Path> {
+        let is_executable =
+            self.target.kind.iter().any(|kind| kind == "bin" || kind == "example");
+        if !is_executable {
+            return None;
+        }
+        self.filenames.first().map(PathBuf::as_path)
+    }
+
+    /// Returns the test binary built from a target when cargo builds tests.
+    pub fn test_executable_path(&self) -> Option<&Path> {
+        if !self.profile.test || self.target.kind.iter().any(|kind| kind == "custom-build") {
+            return None;
+        }
+        self.filenames.first().map(PathBuf::as_path)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+enum CargoMessage {
+    CompilerArtifact(CargoArtifact),
+    CompilerMessage { message: CompilerDiagnostic },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+struct CompilerDiagnostic {
+    rendered: Option<String>,
+}
+
+/// What a line printed by cargo with `--message-format=json` contains.
+#[derive(Debug, PartialEq)]
+pub enum CargoLine {
+    Artifact(CargoArtifact),
+    /// A compiler diagnostic, rendered the way cargo would print it without JSON.
+    Diagnostic(String),
+    /// Any other message from cargo.
+    Message,
+    /// A line that is not a cargo message, i.e. the output of a binary run by cargo.
+    Output,
+}
+
+pub fn parse_cargo_line(line: &str) -> CargoLine {
+    if !line.starts_with('{') {
+        return CargoLine::Output;
+    }
+    match serde_json::from_str(line) {
+        Ok(CargoMessage::CompilerArtifact(artifact)) => CargoLine::Artifact(artifact),
+        Ok(CargoMessage::CompilerMessage { message }) => {
+            CargoLine::Diagnostic(message.rendered.unwrap_or_default())
+        }
+        Ok(CargoMessage::Other) => CargoLine::Message,
+        Err(_) => CargoLine::Output,
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Package {
+    pub id: String,
+    pub name: String,
+    pub manifest_path: PathBuf,
+    pub targets: Vec<Target>,
+    /// The package's `[package.metadata]` table.
+    #[serde(default)]
+    pub metadata: Option<serde_json::Value>,
+}
+
+impl Package {
+    pub fn cdylib_target(&self) -> Option<&Target> {
+        self.targets.iter().find(|target| target.is_cdylib())
+    }
+
+    /// The directory containing the package's `Cargo.toml`.
+    pub fn root(&self) -> &Path {
+        self.manifest_path.parent().unwrap_or(&self.manifest_path)
+    }
+}
+
+/// The parts of the output of `cargo metadata` that fargo uses to find its way
+/// around a workspace.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct CargoMetadata {
+    pub packages: Vec<Package>,
+    pub workspace_members: Vec<String>,
+    pub workspace_root: PathBuf,
+    pub target_directory: PathBuf,
+}
+
+impl CargoMetadata {
+    /// Runs `cargo metadata` for the project containing the current directory.
+    pub fn load(verbose: bool) -> Result<CargoMetadata, Error> {
+        let mut cmd = Command::new("cargo");
+        cmd.args(["metadata", "--format-version", "1", "--no-deps"]);
+        if verbose {
+            println!("cargo metadata: {:?}", cmd);
+        }
+        let output = cmd.output().context("unable to run cargo metadata")?;
+        if !output.status.success() {
+            bail!("cargo metadata failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+        }
+        CargoMetadata::parse(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    pub fn parse(json: &str) -> Result<CargoMetadata, Error> {
+        Ok(serde_json::from_str(json).context("unable to parse cargo metadata")?)
+    }
+
+    pub fn members(&self) -> Vec<&Package> {
+        self.packages
+            .iter()
+            .filter(|package| self.workspace_members.contains(&package.id))
+            .collect()
+    }
+
+    /// Returns the workspace member whose directory contains `dir`, picking the innermost
+    /// one when members are nested.
+    pub fn package_containing(&self, dir: &Path) -> Option<&Package> {
+        self.members()
+            .into_iter()
+            .filter(|package| dir.starts_with(package.root()))
+            .max_by_key(|package| package.root().components().count())
+    }
+
+    /// Returns the packages selected by cargo's `--package` and `--all` flags, or the
+    /// package containing `dir` if neither was given.
+    pub fn select_packages(
+        &self,
+        package_names: &[&str],
+        all: bool,
+        dir: &Path,
+    ) -> Result<Vec<&Package>, Error> {
+        if all {
+            return Ok(self.members());
+        }
+        if package_names.is_empty() {
+            return match self.package_containing(dir) {
+                Some(package) => Ok(vec![package]),
+                None => bail!(
+                    "{:?} is not inside a package of the workspace at {:?}, use --package or \
+                     --all to select packages",
+                    dir,
+                    self.workspace_root
+                ),
+            };
+        }
+        let mut packages = vec![];
+        for package_name in package_names {
+            match self.members().into_iter().find(|package| package.name == *package_name) {
+                Some(package) => packages.push(package),
+                None => bail!("package '{}' is not a member of the workspace", package_name),
+            }
+        }
+        Ok(packages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use metadata::{CargoLine, CargoMetadata, parse_cargo_line};
+    use std::path::Path;
+
+    static METADATA: &str = r#"{
+        "packages": [
+            {
+                "name": "fargo-driver",
+                "version": "0.1.0",
+                "id": "fargo-driver 0.1.0 (path+file:///work/driver)",
+                "manifest_path": "/work/driver/Cargo.toml",
+                "targets": [
+                    {
+                        "kind": ["cdylib"],
+                        "crate_types": ["cdylib"],
+                        "name": "fargo-driver",
+                        "src_path": "/work/driver/src/lib.rs"
+                    }
+                ]
+            },
+            {
+                "name": "fargo-test",
+                "version": "0.1.0",
+                "id": "fargo-test 0.1.0 (path+file:///work/test)",
+                "manifest_path": "/work/test/Cargo.toml",
+                "targets": [
+                    {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "fargo-test",
+                        "src_path": "/work/test/src/main.rs"
+                    }
+                ]
+            }
+        ],
+        "workspace_members": [
+            "fargo-driver 0.1.0 (path+file:///work/driver)",
+            "fargo-test 0.1.0 (path+file:///work/test)"
+        ],
+        "target_directory": "/work/target",
+        "workspace_root": "/work",
+        "version": 1
+    }"#;
+
+    #[test]
+    fn test_select_packages() {
+        let metadata = CargoMetadata::parse(METADATA).unwrap();
+        assert_eq!(metadata.target_directory, Path::new("/work/target"));
+
+        let packages = metadata.select_packages(&[], false, Path::new("/work/driver/src")).unwrap();
+        assert_eq!(packages.len(), 1);
+        assert_eq!(packages[0].name, "fargo-driver");
+        let driver = packages[0].cdylib_target().unwrap();
+        assert_eq!(driver.cdylib_file_name(), "libfargo_driver.so");
+
+        let packages = metadata.select_packages(&["fargo-test"], false, Path::new("/")).unwrap();
+        assert_eq!(packages[0].name, "fargo-test");
+        assert_eq!(packages[0].cdylib_target(), None);
+
+        assert_eq!(metadata.select_packages(&[], true, Path::new("/")).unwrap().len(), 2);
+        assert!(metadata.select_packages(&[], false, Path::new("/work")).is_err());
+        assert!(metadata.select_packages(&["fargo"], false, Path::new("/work")).is_err());
+    }
+
+    #[test]
+    fn test_parse_cargo_line() {
+        let artifact = match parse_cargo_line(
+            r#"{"reason":"compiler-artifact","package_id":"fargo-driver 0.1.0 (path+file:///work/driver)","target":{"kind":["cdylib"],"crate_types":["cdylib"],"name":"fargo-driver","src_path":"/work/driver/src/lib.rs"},"profile":{"opt_level":"0"},"features":[],"filenames":["/work/target/x86_64-fuchsia/debug/libfargo_driver.so"],"fresh":true}"#,
+        ) {
+            CargoLine::Artifact(artifact) => artifact,
+            line => panic!("expected an artifact, got {:?}", line),
+        };
+        assert_eq!(
+            artifact.cdylib_path(),
+            Some(Path::new("/work/target/x86_64-fuchsia/debug/libfargo_driver.so"))
+        );
+        assert_eq!(
+            parse_cargo_line(
+                r#"{"reason":"compiler-message","package_id":"p","target":{},"message":{"rendered":"warning: unused"}}"#
+            ),
+            CargoLine::Diagnostic(String::from("warning: unused"))
+        );
+        assert_eq!(
+            parse_cargo_line(r#"{"reason":"build-script-executed","package_id":"p"}"#),
+            CargoLine::Message
+        );
+        assert_eq!(parse_cargo_line("running 1 test"), CargoLine::Output);
+    }
+}
@@ -0,0 +1,464 @@
+// Copyright 2018 The Fuchsia Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! The built-in ssh client used with `--native-ssh`, only built with the
+//! `native-ssh` feature as it links with libssh2 and OpenSSL.
+
+use device::{SshConfig, Transport, device_socket_address, netaddr, ssh_port, transport_failed};
+use failure::{Error, ResultExt, err_msg};
+use sdk::TargetOptions;
+use ssh2::{self, Channel, ExtendedData, Session};
+use std::{io, time};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::time::Instant;
+use utils::duration_as_milliseconds;
+
+static SSH_CONNECT_TIMEOUT: u64 = 20;
+
+/// A command started over an ssh session. Reading it reads the command's standard
+/// output and error, merged.
+trait RemoteCommand: Read {
+    /// Waits for the command to finish and returns its exit code.
+    fn wait_exit_status(&mut self) -> Result<i32, Error>;
+
+    /// Stops waiting for the command, which may then still be running on the device.
+    fn abandon(&mut self);
+}
+
+/// A file being copied to the device over an ssh session.
+trait RemoteFile: Write {
+    /// Waits until the device has received the whole file.
+    fn finish(&mut self) -> Result<(), Error>;
+}
+
+/// The parts of an ssh session that `NativeSshTransport` uses, so that tests can
+/// replace them. Failures of the session are reported as `TransportFailed`.
+trait SshSession {
+    fn exec(&mut self, command: &str) -> Result<Box<dyn RemoteCommand>, Error>;
+
+    fn create_file(&mut self, path: &str, size: u64) -> Result<Box<dyn RemoteFile>, Error>;
+
+    /// Sets how long blocking operations of the session wait, zero meaning forever.
+    fn set_timeout(&mut self, timeout_ms: u32);
+}
+
+fn ssh_failed(action: &str, error: ssh2::Error) -> Error {
+    transport_failed(format!("{} failed: {}", action, error)).into()
+}
+
+impl RemoteCommand for Channel {
+    fn wait_exit_status(&mut self) -> Result<i32, Error> {
+        self.wait_close().map_err(|e| ssh_failed("waiting for the command", e))?;
+        self.exit_status().map_err(|e| ssh_failed("reading the exit status", e))
+    }
+
+    fn abandon(&mut self) {
+        self.close().ok();
+    }
+}
+
+impl RemoteFile for Channel {
+    fn finish(&mut self) -> Result<(), Error> {
+        self.send_eof()
+            .and_then(|_| self.wait_eof())
+            .and_then(|_| self.close())
+            .and_then(|_| self.wait_close())
+            .map_err(|e| ssh_failed("finishing the copy", e))
+    }
+}
+
+impl SshSession for Session {
+    fn exec(&mut self, command: &str) -> Result<Box<dyn RemoteCommand>, Error> {
+        let mut channel =
+            self.channel_session().map_err(|e| ssh_failed("opening an ssh channel", e))?;
+        channel.handle_extended_data(ExtendedData::Merge).map_err(
+            |e| ssh_failed("merging the command's output", e),
+        )?;
+        channel.exec(command).map_err(|e| ssh_failed(&format!("running {}", command), e))?;
+        Ok(Box::new(channel))
+    }
+
+    fn create_file(&mut self, path: &str, size: u64) -> Result<Box<dyn RemoteFile>, Error> {
+        let channel = self.scp_send(Path::new(path), 0o755, size, None).map_err(|e| {
+            ssh_failed(&format!("starting to copy to {}", path), e)
+        })?;
+        Ok(Box::new(channel))
+    }
+
+    fn set_timeout(&mut self, timeout_ms: u32) {
+        Session::set_timeout(self, timeout_ms);
+    }
+}
+
+/// Transport that keeps a single in-process SSH session open for the lifetime of the
+/// transport, so copying a binary and running it costs only one connection.
+pub struct NativeSshTransport {
+    verbose: bool,
+    session: Box<dyn SshSession>,
+}
+
+impl NativeSshTransport {
+    pub fn connect(
+        verbose: bool,
+        target_options: &TargetOptions,
+    ) -> Result<NativeSshTransport, Error> {
+        let netaddr = netaddr(verbose, target_options)?;
+        let session = ssh_session(
+            verbose,
+            target_options,
+            &netaddr,
+            time::Duration::from_secs(SSH_CONNECT_TIMEOUT),
+        )?;
+        Ok(NativeSshTransport {
+            verbose,
+            session: Box::new(session),
+        })
+    }
+}
+
+fn output_failed(command: &str, error: io::Error) -> Error {
+    transport_failed(format!("reading the output of {} failed: {}", command, error)).into()
+}
+
+/// Opens an ssh session with the device at `netaddr`, authenticating with the identity
+/// from the ssh config of the Fuchsia build selected by `target_options`. Connecting,
+/// the handshake and authentication each give up after `timeout`.
+fn ssh_session(
+    verbose: bool,
+    target_options: &TargetOptions,
+    netaddr: &str,
+    timeout: time::Duration,
+) -> Result<Session, Error> {
+    let ssh_config = SshConfig::load(target_options)?;
+    let user = ssh_config.user.unwrap_or_else(|| String::from("fuchsia"));
+    let identity_file = ssh_config.identity_file.ok_or_else(|| {
+        err_msg("ssh config does not specify an IdentityFile")
+    })?;
+    let port = ssh_port(target_options, ssh_config.port)?;
+    if verbose {
+        println!("native ssh: connecting to {}@[{}]:{}", user, netaddr, port);
+    }
+
+    let address = device_socket_address(netaddr, port)?;
+    let tcp = TcpStream::connect_timeout(&address, timeout)
+        .map_err(|e| transport_failed(format!("unable to connect to {}: {}", address, e)))?;
+    let mut session = Session::new().context("unable to create ssh session")?;
+    session.set_tcp_stream(tcp);
+    session.set_timeout(duration_as_milliseconds(&timeout) as u32);
+    session.handshake().map_err(
+        |e| transport_failed(format!("ssh handshake failed: {}", e)),
+    )?;
+    session.userauth_pubkey_file(&user, None, &identity_file, None).map_err(|e| {
+        transport_failed(format!(
+            "ssh authentication as {} with {:?} failed: {}",
+            user,
+            identity_file,
+            e
+        ))
+    })?;
+    session.set_timeout(0);
+    Ok(session)
+}
+
+/// Sets the timeout of an ssh session for a single command and clears it again when
+/// dropped, however the command ends.
+struct SessionTimeout<'a> {
+    session: &'a mut dyn SshSession,
+}
+
+impl<'a> SessionTimeout<'a> {
+    fn set_timeout(&mut self, timeout_ms: u32) {
+        self.session.set_timeout(timeout_ms);
+    }
+}
+
+impl<'a> Drop for SessionTimeout<'a> {
+    fn drop(&mut self) {
+        self.session.set_timeout(0);
+    }
+}
+
+impl Transport for NativeSshTransport {
+    fn copy_to_device(&mut self, source_path: &Path, destination_path: &str) -> Result<(), Error> {
+        let mut source_file =
+            File::open(source_path).context(format!("unable to open {:?}", source_path))?;
+        let metadata = source_file.metadata()?;
+        if self.verbose {
+            println!("native scp: {:?} to {}", source_path, destination_path);
+        }
+        let mut remote_file = self.session.create_file(destination_path, metadata.len())?;
+        io::copy(&mut source_file, &mut remote_file).map_err(|e| {
+            transport_failed(format!(
+                "copying {:?} to {} failed: {}",
+                source_path,
+                destination_path,
+                e
+            ))
+        })?;
+        remote_file.finish()
+    }
+
+    fn run(&mut self, command: &str) -> Result<i32, Error> {
+        self.run_with_output(command, &mut |line| {
+            println!("{}", line);
+            Ok(())
+        })
+    }
+
+    fn run_with_output(
+        &mut self,
+        command: &str,
+        on_line: &mut dyn FnMut(&str) -> Result<(), Error>,
+    ) -> Result<i32, Error> {
+        if self.verbose {
+            println!("native ssh: running {}", command);
+        }
+        let mut remote_command = self.session.exec(command)?;
+        for line in BufReader::new(&mut remote_command).lines() {
+            on_line(&line.map_err(|e| output_failed(command, e))?)?;
+        }
+        remote_command.wait_exit_status()
+    }
+
+    fn run_with_timeout(
+        &mut self,
+        command: &str,
+        on_line: &mut dyn FnMut(&str) -> Result<(), Error>,
+        timeout: time::Duration,
+    ) -> Result<Option<i32>, Error> {
+        if self.verbose {
+            println!("native ssh: running {} with a timeout of {:?}", command, timeout);
+        }
+        let deadline = Instant::now() + timeout;
+        let mut remote_command = self.session.exec(command)?;
+        let mut timed_out = false;
+        {
+            let mut session = SessionTimeout { session: &mut *self.session };
+            let mut lines = BufReader::new(&mut remote_command).lines();
+            loop {
+                // libssh2 applies the timeout to every blocking call, so it is set to
+                // whatever is left before each read. Zero would mean no timeout.
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                let remaining_ms = duration_as_milliseconds(&remaining);
+                if remaining_ms == 0 {
+                    timed_out = true;
+                    break;
+                }
+                session.set_timeout(remaining_ms as u32);
+                match lines.next() {
+                    Some(Ok(line)) => on_line(&line)?,
+                    Some(Err(_)) if Instant::now() >= deadline => {
+                        timed_out = true;
+                        break;
+                    }
+                    Some(Err(error)) => return Err(output_failed(command, error)),
+                    None => break,
+                }
+            }
+        }
+        if timed_out {
+            remote_command.abandon();
+            return Ok(None);
+        }
+        Ok(Some(remote_command.wait_exit_status()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use device::Transport;
+    use error::is_transport_failure;
+    use failure::{Error, err_msg};
+    use native_ssh::{NativeSshTransport, RemoteCommand, RemoteFile, SshSession};
+    use sdk::TargetOptions;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{self, Cursor, Read, Write};
+    use std::net::TcpListener;
+    use std::process;
+    use std::thread;
+    use std::time::Duration;
+    use test_util::{FakeDevice, FakeRun};
+
+    /// A command that prints `output` and exits with `exit_code`, or loses the
+    /// connection after printing if there is no exit code.
+    struct FakeCommand {
+        output: Cursor<Vec<u8>>,
+        exit_code: Option<i32>,
+    }
+
+    impl Read for FakeCommand {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = self.output.read(buf)?;
+            if len == 0 && self.exit_code.is_none() {
+                return Err(io::Error::new(io::ErrorKind::ConnectionReset, "connection reset"));
+            }
+            Ok(len)
+        }
+    }
+
+    impl RemoteCommand for FakeCommand {
+        fn wait_exit_status(&mut self) -> Result<i32, Error> {
+            Ok(self.exit_code.unwrap())
+        }
+
+        fn abandon(&mut self) {}
+    }
+
+    struct FakeFile {
+        path: String,
+        contents: Vec<u8>,
+        device: FakeDevice,
+    }
+
+    impl Write for FakeFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.contents.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl RemoteFile for FakeFile {
+        fn finish(&mut self) -> Result<(), Error> {
+            self.device.copy(&self.path, self.contents.clone());
+            Ok(())
+        }
+    }
+
+    impl SshSession for FakeDevice {
+        fn exec(&mut self, command: &str) -> Result<Box<dyn RemoteCommand>, Error> {
+            let (output, exit_code) = match self.start(command) {
+                FakeRun::Exit(output, exit_code) => (output, Some(exit_code)),
+                FakeRun::ConnectionLost(output) => (output, None),
+                FakeRun::Failure => return Err(err_msg("unable to run the command")),
+            };
+            Ok(Box::new(FakeCommand {
+                output: Cursor::new(output.into_bytes()),
+                exit_code,
+            }))
+        }
+
+        fn create_file(&mut self, path: &str, _size: u64) -> Result<Box<dyn RemoteFile>, Error> {
+            Ok(Box::new(FakeFile {
+                path: path.to_string(),
+                contents: vec![],
+                device: self.clone(),
+            }))
+        }
+
+        fn set_timeout(&mut self, timeout_ms: u32) {
+            self.set_timeout_ms(timeout_ms);
+        }
+    }
+
+    fn fake_transport(device: &FakeDevice) -> NativeSshTransport {
+        NativeSshTransport {
+            verbose: false,
+            session: Box::new(device.clone()),
+        }
+    }
+
+    fn run_collecting_lines(
+        transport: &mut NativeSshTransport,
+        on_line_result: fn() -> Result<(), Error>,
+    ) -> Result<(i32, Vec<String>), Error> {
+        let mut lines = vec![];
+        let exit_code = transport.run_with_output("test", &mut |line| {
+            lines.push(line.to_string());
+            on_line_result()
+        })?;
+        Ok((exit_code, lines))
+    }
+
+    #[test]
+    fn test_native_ssh_copy() {
+        let source_path = env::temp_dir().join(format!("fargo-native-ssh-copy-{}", process::id()));
+        File::create(&source_path).unwrap().write_all(b"\x7fELF").unwrap();
+        let device = FakeDevice::default();
+        let copied = fake_transport(&device).copy_to_device(&source_path, "/tmp/test_binary");
+        fs::remove_file(&source_path).unwrap();
+        copied.unwrap();
+        assert_eq!(
+            device.copied_files(),
+            vec![(String::from("/tmp/test_binary"), b"\x7fELF".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_native_ssh_exit_status() {
+        let device = FakeDevice::default();
+        for _ in 0..2 {
+            device.push_run(FakeRun::Exit(String::from("running 1 test\ntest ok\n"), 101));
+        }
+        let mut transport = fake_transport(&device);
+        let (exit_code, lines) = run_collecting_lines(&mut transport, || Ok(())).unwrap();
+        assert_eq!(exit_code, 101);
+        assert_eq!(lines, vec!["running 1 test", "test ok"]);
+        let timeout = Duration::from_secs(60);
+        let exit_code = transport.run_with_timeout("test", &mut |_| Ok(()), timeout).unwrap();
+        assert_eq!(exit_code, Some(101));
+    }
+
+    #[test]
+    fn test_native_ssh_timeout_reset() {
+        let device = FakeDevice::default();
+        for _ in 0..2 {
+            device.push_run(FakeRun::Exit(String::from("running 1 test\n"), 0));
+        }
+        let mut transport = fake_transport(&device);
+        let timeout = Duration::from_secs(60);
+        transport.run_with_timeout("test", &mut |_| Ok(()), timeout).unwrap();
+        assert_eq!(device.timeout_ms(), 0);
+        let result = transport.run_with_timeout("test", &mut |_| Err(err_msg("bad line")), timeout);
+        assert!(result.is_err());
+        assert_eq!(device.timeout_ms(), 0);
+    }
+
+    #[test]
+    fn test_native_ssh_transport_failures() {
+        // Losing the connection while a command runs is a transport failure, a failure
+        // to handle its output is not.
+        let device = FakeDevice::default();
+        device.push_run(FakeRun::ConnectionLost(String::from("running 1 test\n")));
+        device.push_run(FakeRun::Exit(String::from("running 1 test\n"), 0));
+        let mut transport = fake_transport(&device);
+        let error = run_collecting_lines(&mut transport, || Ok(())).unwrap_err();
+        assert!(is_transport_failure(&error));
+        let error = run_collecting_lines(&mut transport, || Err(err_msg("bad line"))).unwrap_err();
+        assert!(!is_transport_failure(&error));
+
+        let ssh_config_path =
+            env::temp_dir().join(format!("fargo-native-ssh-config-{}", process::id()));
+        File::create(&ssh_config_path).unwrap().write_all(b"IdentityFile /dev/null\n").unwrap();
+        let connect = |address: &str| {
+            let mut target_options = TargetOptions::new(true, "x64", None);
+            target_options.device_address = Some(address);
+            target_options.ssh_config = Some(&ssh_config_path);
+            NativeSshTransport::connect(false, &target_options).map(|_| ())
+        };
+
+        // Nothing listening on the port.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed_address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let refused = connect(&closed_address);
+
+        // Something that isn't an ssh server listening on the port.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || drop(listener.accept().unwrap()));
+        let no_handshake = connect(&address);
+        server.join().unwrap();
+
+        fs::remove_file(&ssh_config_path).unwrap();
+        assert!(is_transport_failure(&refused.unwrap_err()));
+        assert!(is_transport_failure(&no_handshake.unwrap_err()));
+    }
+}
@@ -0,0 +1,256 @@
+// Copyright 2018 The Fuchsia Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use failure::{Error, ResultExt};
+use libc;
+use std::ffi::CStr;
+use std::io::{self, Cursor};
+use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6, UdpSocket};
+use std::process;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const NB_MAGIC: u32 = 0xAA77_4217;
+const NB_SERVER_PORT: u16 = 33330;
+const NB_QUERY: u32 = 5;
+const NB_ACK: u32 = 0;
+const NB_HEADER_LEN: usize = 16;
+
+/// How long to wait for devices to answer a query, the same as `netls --timeout=500`.
+const DISCOVERY_TIMEOUT_MS: u64 = 500;
+
+/// A Fuchsia device that answered a netboot query.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Device {
+    pub nodename: String,
+    pub address: Ipv6Addr,
+    /// The network interface the device was found on, empty if it has none, i.e. for
+    /// a device answering on loopback.
+    pub interface: String,
+}
+
+impl Device {
+    /// The address ssh and scp reach the device at, scoped to its interface.
+    pub fn netaddr(&self) -> String {
+        if self.interface.is_empty() {
+            self.address.to_string()
+        } else {
+            format!("{}%{}", self.address, self.interface)
+        }
+    }
+}
+
+fn query_packet(cookie: u32, nodename: &str) -> Vec<u8> {
+    let mut packet = vec![];
+    for &word in &[NB_MAGIC, cookie, NB_QUERY, 0] {
+        packet.write_u32::<LittleEndian>(word).unwrap();
+    }
+    packet.extend_from_slice(nodename.as_bytes());
+    packet.push(0);
+    packet
+}
+
+/// Returns the nodename in an answer to the query with `cookie`, or `None` if the
+/// packet is anything else.
+fn parse_answer(packet: &[u8], cookie: u32) -> Option<String> {
+    if packet.len() < NB_HEADER_LEN {
+        return None;
+    }
+    let mut header = Cursor::new(packet);
+    let mut word = || header.read_u32::<LittleEndian>().unwrap();
+    if word() != NB_MAGIC || word() != cookie || word() != NB_ACK || word() != 0 {
+        return None;
+    }
+    let data = &packet[NB_HEADER_LEN..];
+    let nodename = &data[..data.iter().position(|&b| b == 0).unwrap_or(data.len())];
+    if nodename.is_empty() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(nodename).into_owned())
+}
+
+/// Returns the index of every network interface of the host.
+fn interface_indices() -> Vec<u32> {
+    let mut indices = vec![];
+    unsafe {
+        let interfaces = libc::if_nameindex();
+        if interfaces.is_null() {
+            return indices;
+        }
+        let mut interface = interfaces;
+        while (*interface).if_index != 0 {
+            indices.push((*interface).if_index);
+            interface = interface.offset(1);
+        }
+        libc::if_freenameindex(interfaces);
+    }
+    indices
+}
+
+fn interface_name(index: u32) -> String {
+    if index == 0 {
+        return String::new();
+    }
+    let mut name = [0 as libc::c_char; libc::IF_NAMESIZE];
+    unsafe {
+        if libc::if_indextoname(index, name.as_mut_ptr()).is_null() {
+            return index.to_string();
+        }
+        CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned()
+    }
+}
+
+/// Sends a query for the device named `nodename`, or for every device if there is
+/// none, to each of `destinations`, and collects the answers that arrive within
+/// `timeout`. Stops at the first answer when looking for a named device.
+fn query_devices(
+    verbose: bool,
+    destinations: &[SocketAddrV6],
+    nodename: Option<&str>,
+    timeout: Duration,
+) -> Result<Vec<Device>, Error> {
+    let any_address = SocketAddrV6::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0), 0, 0, 0);
+    let socket = UdpSocket::bind(any_address)
+        .context("unable to open a socket for device discovery")?;
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let cookie = process::id() ^ since_epoch.subsec_nanos();
+    let packet = query_packet(cookie, nodename.unwrap_or("*"));
+
+    let mut sent = 0;
+    for destination in destinations {
+        // Interfaces that are down or don't do IPv6 can't send the query, which only
+        // matters if none of them can.
+        match socket.send_to(&packet, destination) {
+            Ok(_) => sent += 1,
+            Err(error) => if verbose {
+                eprintln!("netboot query to {} failed: {}", destination, error);
+            },
+        }
+    }
+    if sent == 0 {
+        bail!("unable to send a device discovery query on any network interface");
+    }
+
+    let deadline = Instant::now() + timeout;
+    let mut devices: Vec<Device> = vec![];
+    let mut answer = [0; 1500];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        socket.set_read_timeout(Some(deadline - now))?;
+        let (len, source) = match socket.recv_from(&mut answer) {
+            Ok(received) => received,
+            Err(ref error)
+                if error.kind() == io::ErrorKind::WouldBlock ||
+                       error.kind() == io::ErrorKind::TimedOut =>
+            {
+                break;
+            }
+            Err(error) => return Err(error.into()),
+        };
+        let source = match source {
+            SocketAddr::V6(source) => source,
+            SocketAddr::V4(_) => continue,
+        };
+        if let Some(answer_nodename) = parse_answer(&answer[..len], cookie) {
+            let device = Device {
+                nodename: answer_nodename,
+                address: *source.ip(),
+                interface: interface_name(source.scope_id()),
+            };
+            if verbose {
+                eprintln!("netboot: found {} at {}", device.nodename, device.netaddr());
+            }
+            if !devices.contains(&device) {
+                devices.push(device);
+            }
+            if nodename.is_some() {
+                break;
+            }
+        }
+    }
+    Ok(devices)
+}
+
+fn discover(verbose: bool, nodename: Option<&str>) -> Result<Vec<Device>, Error> {
+    // Devices listen on the all-nodes link-local multicast group, which has to be
+    // addressed separately on every interface.
+    let all_nodes = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+    let destinations: Vec<SocketAddrV6> = interface_indices()
+        .into_iter()
+        .map(|index| SocketAddrV6::new(all_nodes, NB_SERVER_PORT, 0, index))
+        .collect();
+    query_devices(verbose, &destinations, nodename, Duration::from_millis(DISCOVERY_TIMEOUT_MS))
+}
+
+/// Finds the Fuchsia devices on the local network with the netboot discovery protocol
+/// that `netls` uses.
+pub fn discover_devices(verbose: bool) -> Result<Vec<Device>, Error> {
+    discover(verbose, None)
+}
+
+/// Finds the device named `nodename`, like `netaddr <nodename>` does.
+pub fn find_device(verbose: bool, nodename: &str) -> Result<Option<Device>, Error> {
+    Ok(discover(verbose, Some(nodename))?.into_iter().next())
+}
+
+#[cfg(test)]
+mod tests {
+    use netboot::{Device, parse_answer, query_devices, query_packet};
+    use std::net::{Ipv6Addr, SocketAddr, UdpSocket};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_answer() {
+        let mut answer = query_packet(42, "ivy-donut-grew-stoop");
+        assert_eq!(parse_answer(&answer, 42), None);
+        answer[8] = 0;
+        assert_eq!(parse_answer(&answer, 42), Some(String::from("ivy-donut-grew-stoop")));
+        assert_eq!(parse_answer(&answer, 43), None);
+        assert_eq!(parse_answer(&answer[..12], 42), None);
+    }
+
+    #[test]
+    fn test_query_devices() {
+        // A fake device answering on loopback as netsvc would.
+        let responder = match UdpSocket::bind("[::1]:0") {
+            Ok(responder) => responder,
+            Err(error) => {
+                eprintln!("skipping test_query_devices, IPv6 loopback is unavailable: {}", error);
+                return;
+            }
+        };
+        let responder_address = match responder.local_addr().unwrap() {
+            SocketAddr::V6(address) => address,
+            SocketAddr::V4(_) => unreachable!(),
+        };
+        let fake_device = thread::spawn(move || {
+            let mut query = [0; 1500];
+            let (len, source) = responder.recv_from(&mut query).unwrap();
+            assert_eq!(&query[16..len], b"*\0");
+            let mut answer = query[..16].to_vec();
+            answer[8..12].copy_from_slice(&[0, 0, 0, 0]);
+            answer.extend_from_slice(b"fuchsia-test\0");
+            responder.send_to(&answer, source).unwrap();
+        });
+
+        let devices =
+            query_devices(false, &[responder_address], None, Duration::from_millis(500)).unwrap();
+        fake_device.join().unwrap();
+        assert_eq!(
+            devices,
+            vec![
+                Device {
+                    nodename: String::from("fuchsia-test"),
+                    address: Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1),
+                    interface: String::new(),
+                },
+            ]
+        );
+        assert_eq!(devices[0].netaddr(), "::1");
+    }
+}
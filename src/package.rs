@@ -0,0 +1,160 @@
+// Copyright 2018 The Fuchsia Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use component::{ComponentConfig, component_manifest};
+use device::Transport;
+use far::write_far;
+use failure::{Error, ResultExt};
+use merkle::merkle_root;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// A Fuchsia package built by fargo, made of a `meta.far` describing the package
+/// and the blobs it refers to by merkle root.
+#[derive(Debug)]
+pub struct FuchsiaPackage {
+    pub name: String,
+    /// The package archive, containing `meta.far` and every blob.
+    pub archive_path: PathBuf,
+    meta_far: Vec<u8>,
+    blobs: BTreeMap<String, Vec<u8>>,
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, Error> {
+    let mut contents = vec![];
+    File::open(path)
+        .context(format!("unable to open {:?}", path))?
+        .read_to_end(&mut contents)?;
+    Ok(contents)
+}
+
+/// Returns the URL `run` uses to start the component of a package built by fargo.
+pub fn component_url(package_name: &str) -> String {
+    format!("fuchsia-pkg://fuchsia.com/{}#meta/{}.cmx", package_name, package_name)
+}
+
+/// Builds a package named `name` containing `binary` as `bin/<name>` and each
+/// resource as `data/<resource>`, where resources are relative to `resource_root`,
+/// and writes its archive to `out_dir/<name>.far`. The component manifest of the
+/// package is generated from `component`.
+pub fn build_package(
+    name: &str,
+    binary: &Path,
+    resource_root: &Path,
+    resources: &[String],
+    component: &ComponentConfig,
+    out_dir: &Path,
+) -> Result<FuchsiaPackage, Error> {
+    let binary_path = format!("bin/{}", name);
+    let mut files = BTreeMap::new();
+    files.insert(binary_path.clone(), read_file(binary)?);
+    for resource in resources {
+        files.insert(format!("data/{}", resource), read_file(&resource_root.join(resource))?);
+    }
+
+    let mut contents = String::new();
+    let mut blobs = BTreeMap::new();
+    for (path, content) in files {
+        let merkle = merkle_root(&content);
+        contents.push_str(&format!("{}={}\n", path, merkle));
+        blobs.insert(merkle, content);
+    }
+
+    let mut meta = BTreeMap::new();
+    meta.insert(
+        String::from("meta/package"),
+        format!("{{\"name\":\"{}\",\"version\":\"0\"}}\n", name).into_bytes(),
+    );
+    meta.insert(String::from("meta/contents"), contents.into_bytes());
+    meta.insert(
+        format!("meta/{}.cmx", name),
+        component_manifest(&binary_path, component)?.into_bytes(),
+    );
+    let mut meta_far = vec![];
+    write_far(&meta, &mut meta_far)?;
+
+    let mut archive = BTreeMap::new();
+    archive.insert(String::from("meta.far"), meta_far.clone());
+    for (merkle, content) in &blobs {
+        archive.insert(merkle.clone(), content.clone());
+    }
+    fs::create_dir_all(out_dir).context(format!("unable to create {:?}", out_dir))?;
+    let archive_path = out_dir.join(format!("{}.far", name));
+    let mut archive_file =
+        File::create(&archive_path).context(format!("unable to create {:?}", archive_path))?;
+    write_far(&archive, &mut archive_file)?;
+
+    Ok(FuchsiaPackage {
+        name: name.to_string(),
+        archive_path,
+        meta_far,
+        blobs,
+    })
+}
+
+fn write_to_device(
+    transport: &mut dyn Transport,
+    content: &[u8],
+    host_path: &Path,
+    device_path: &str,
+) -> Result<(), Error> {
+    fs::write(host_path, content).context(format!("unable to write {:?}", host_path))?;
+    transport.copy_to_device(host_path, device_path)
+}
+
+/// Installs a package through pkgfs: writing `meta.far` makes pkgfs list the blobs
+/// it does not have yet under `/pkgfs/needs`, and only those are copied.
+pub fn install_package(
+    verbose: bool,
+    package: &FuchsiaPackage,
+    transport: &mut dyn Transport,
+) -> Result<(), Error> {
+    let staging_dir = package.archive_path.with_extension("install");
+    fs::create_dir_all(&staging_dir).context(format!("unable to create {:?}", staging_dir))?;
+
+    let meta_far_merkle = merkle_root(&package.meta_far);
+    if verbose {
+        println!("installing {} as {}", package.name, meta_far_merkle);
+    }
+    write_to_device(
+        transport,
+        &package.meta_far,
+        &staging_dir.join("meta.far"),
+        &format!("/pkgfs/install/pkg/{}", meta_far_merkle),
+    )?;
+
+    // pkgfs removes the needs directory of a package once it has all of its blobs.
+    let needs_dir = format!("/pkgfs/needs/packages/{}", meta_far_merkle);
+    let mut needed = vec![];
+    let exit_code = transport.run_with_output(
+        &format!("[ ! -d {0} ] || ls {0}", needs_dir),
+        &mut |line| {
+            needed.push(line.trim().to_string());
+            Ok(())
+        },
+    )?;
+    if exit_code != 0 {
+        bail!("unable to list the blobs {} needs: ls exited with {}", package.name, exit_code);
+    }
+    for merkle in &needed {
+        match package.blobs.get(merkle) {
+            Some(content) => write_to_device(
+                transport,
+                content,
+                &staging_dir.join(merkle),
+                &format!("/pkgfs/install/blob/{}", merkle),
+            )?,
+            None => bail!("{} needs blob {}, which is not in the package", package.name, merkle),
+        }
+    }
+
+    let package_dir = format!("/pkgfs/packages/{}/0", package.name);
+    let exit_code = transport.run_with_output(&format!("[ -d {} ]", package_dir), &mut |_| Ok(()))?;
+    if exit_code != 0 {
+        bail!("{} was not installed, {} is missing", package.name, package_dir);
+    }
+    Ok(())
+}
@@ -0,0 +1,224 @@
+// Copyright 2018 The Fuchsia Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use failure::{Error, ResultExt};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use toml;
+use utils::format_table;
+
+/// The Fuchsia builds a registered device can run.
+static VARIANTS: &[&str] = &["release", "debug"];
+
+/// A device registered with `fargo device add`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct RegisteredDevice {
+    /// The name the device answers discovery with, if it differs from its alias.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nodename: Option<String>,
+    /// A fixed address, for devices that can't be found on the network.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    /// An ssh config to use instead of the one from the Fuchsia build.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ssh_config: Option<PathBuf>,
+    /// The Fuchsia build the device runs, `release` or `debug`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+}
+
+impl RegisteredDevice {
+    pub fn is_release(&self) -> Option<bool> {
+        self.variant.as_ref().map(|variant| variant == "release")
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if let Some(ref variant) = self.variant {
+            if !VARIANTS.contains(&variant.as_str()) {
+                bail!("unknown variant '{}', expected one of: {}", variant, VARIANTS.join(", "));
+            }
+        }
+        // A relative path would depend on the directory fargo happens to run in.
+        if let Some(ref ssh_config) = self.ssh_config {
+            if !ssh_config.is_absolute() {
+                bail!("the ssh config path {:?} is not absolute", ssh_config);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The devices registered in `~/.fargo/devices.toml`, by alias.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceRegistry {
+    /// The alias of the device used when no device name is given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default: Option<String>,
+    #[serde(default)]
+    pub devices: BTreeMap<String, RegisteredDevice>,
+}
+
+fn registry_path() -> Result<PathBuf, Error> {
+    let home_value = env::var("HOME")?;
+    Ok(PathBuf::from(home_value).join(".fargo").join("devices.toml"))
+}
+
+impl DeviceRegistry {
+    pub fn load() -> Result<DeviceRegistry, Error> {
+        let path = registry_path()?;
+        if !path.exists() {
+            return Ok(DeviceRegistry::default());
+        }
+        let mut contents = String::new();
+        File::open(&path)
+            .context(format!("unable to open {:?}", path))?
+            .read_to_string(&mut contents)?;
+        Ok(DeviceRegistry::parse(&contents).context(format!("invalid device registry {:?}", path))?)
+    }
+
+    pub fn parse(contents: &str) -> Result<DeviceRegistry, Error> {
+        let registry: DeviceRegistry = toml::from_str(contents)?;
+        for device in registry.devices.values() {
+            device.validate()?;
+        }
+        Ok(registry)
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        let path = registry_path()?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut registry_file =
+            File::create(&path).context(format!("unable to create {:?}", path))?;
+        registry_file.write_all(toml::to_string_pretty(self)?.as_bytes())?;
+        Ok(())
+    }
+
+    /// Registers a device under `alias`, replacing any device registered under it before.
+    pub fn add(&mut self, alias: &str, device: RegisteredDevice) -> Result<(), Error> {
+        device.validate()?;
+        self.devices.insert(alias.to_string(), device);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, alias: &str) -> Result<(), Error> {
+        if self.devices.remove(alias).is_none() {
+            bail!("no device is registered as '{}'", alias);
+        }
+        if self.default.as_deref() == Some(alias) {
+            self.default = None;
+        }
+        Ok(())
+    }
+
+    pub fn set_default(&mut self, alias: &str) -> Result<(), Error> {
+        if !self.devices.contains_key(alias) {
+            bail!("no device is registered as '{}'", alias);
+        }
+        self.default = Some(alias.to_string());
+        Ok(())
+    }
+
+    /// Returns the registered device `device_name` refers to, either by its alias or
+    /// by its nodename, or the default device if no name is given. A name that is not
+    /// registered is a nodename to find on the network, so it resolves to nothing.
+    pub fn resolve(&self, device_name: Option<&str>) -> Option<(&str, &RegisteredDevice)> {
+        let device_name = match device_name {
+            Some(device_name) => device_name,
+            None => self.default.as_ref()?,
+        };
+        self.devices
+            .iter()
+            .find(|&(alias, device)| {
+                alias == device_name ||
+                    device.nodename.as_deref() == Some(device_name)
+            })
+            .map(|(alias, device)| (alias.as_str(), device))
+    }
+
+    /// Formats the registered devices as a table, marking the default one.
+    pub fn format_table(&self) -> String {
+        let mut rows = vec![
+            vec![
+                String::from("ALIAS"),
+                String::from("NODENAME"),
+                String::from("ADDRESS"),
+                String::from("SSH CONFIG"),
+                String::from("VARIANT"),
+            ],
+        ];
+        let or_dash = |value: Option<String>| value.unwrap_or_else(|| String::from("-"));
+        for (alias, device) in &self.devices {
+            let is_default = self.default.as_ref() == Some(alias);
+            rows.push(vec![
+                if is_default { format!("{} *", alias) } else { alias.clone() },
+                or_dash(device.nodename.clone()),
+                or_dash(device.address.clone()),
+                or_dash(device.ssh_config.as_ref().map(|path| path.to_string_lossy().into_owned())),
+                or_dash(device.variant.clone()),
+            ]);
+        }
+        format_table(&rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use registry::{DeviceRegistry, RegisteredDevice};
+    use std::path::PathBuf;
+    use toml;
+
+    #[test]
+    fn test_registry() {
+        let mut registry = DeviceRegistry::parse(
+            r#"
+            default = "lab"
+
+            [devices.lab]
+            nodename = "ivy-donut-grew-stoop"
+            variant = "debug"
+
+            [devices.usb]
+            address = "192.168.42.2"
+            ssh-config = "/home/user/usb_ssh_config"
+            "#,
+        ).unwrap();
+
+        let (alias, device) = registry.resolve(None).unwrap();
+        assert_eq!(alias, "lab");
+        assert_eq!(device.is_release(), Some(false));
+        assert_eq!(registry.resolve(Some("ivy-donut-grew-stoop")).unwrap().0, "lab");
+        assert_eq!(
+            registry.resolve(Some("usb")).unwrap().1.address,
+            Some(String::from("192.168.42.2"))
+        );
+        assert_eq!(registry.resolve(Some("shun-gully-wrath-bunny")), None);
+
+        registry.remove("lab").unwrap();
+        assert_eq!(registry.default, None);
+        assert_eq!(registry.resolve(None), None);
+        assert!(registry.remove("lab").is_err());
+        assert!(registry.set_default("lab").is_err());
+
+        let invalid = RegisteredDevice {
+            variant: Some(String::from("fast")),
+            ..RegisteredDevice::default()
+        };
+        assert!(registry.add("qemu", invalid).is_err());
+        let relative_ssh_config = RegisteredDevice {
+            ssh_config: Some(PathBuf::from("usb_ssh_config")),
+            ..RegisteredDevice::default()
+        };
+        assert!(registry.add("usb", relative_ssh_config).is_err());
+
+        let saved = toml::to_string_pretty(&registry).unwrap();
+        assert_eq!(DeviceRegistry::parse(&saved).unwrap(), registry);
+    }
+}
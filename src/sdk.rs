@@ -2,25 +2,43 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use error::FargoError;
 use failure::Error;
+use registry::DeviceRegistry;
+use sha2::{Digest, Sha256};
 use std::env;
 use std::fs::File;
 use std::io::Read;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use utils::is_mac;
 
 /// The `TargetOptions` struct bundles together a number of parameters specific to
-/// the Fuchsia target that need to be passed through various internal functions. For
-/// the moment there is no way to set anything but the `release_os` field, but this
-/// will change when fargo starts supporting ARM targets.
-#[derive(Debug)]
+/// the Fuchsia target that need to be passed through various internal functions.
+#[derive(Clone, Debug)]
 pub struct TargetOptions<'a> {
     pub release_os: bool,
     pub target_cpu: &'a str,
     pub target_cpu_linker: &'a str,
     pub device_name: Option<&'a str>,
+    /// Use the in-process ssh client rather than the system `ssh` and `scp` binaries.
+    pub native_ssh: bool,
+    /// A fixed address for the device, used instead of finding it on the network, as
+    /// `host`, `host:port` or `[host]:port`.
+    pub device_address: Option<&'a str>,
+    /// An ssh config to use instead of the one from the Fuchsia build.
+    pub ssh_config: Option<&'a Path>,
+    /// The socket of the ssh control master of the run, for ssh and scp to share.
+    pub control_path: Option<&'a Path>,
+}
+
+fn target_cpu_linker(target_cpu: &str) -> &'static str {
+    if target_cpu == "arm64" { "aarch64" } else { "x86_64" }
 }
 
+/// The longest key `TargetOptions::device_key` returns, which keeps the ssh control
+/// socket paths made from it within the length limit of unix socket paths.
+const MAX_DEVICE_KEY_LEN: usize = 32;
+
 impl<'a> TargetOptions<'a> {
     /// Constructs a new `TargetOptions`.
     ///
@@ -29,16 +47,83 @@ impl<'a> TargetOptions<'a> {
     /// ```
     /// use fargo::TargetOptions;
     ///
-    /// let target_options = TargetOptions::new(true, Some("ivy-donut-grew-stoop"));
+    /// let target_options = TargetOptions::new(true, "x64", Some("ivy-donut-grew-stoop"));
     /// ```
+    pub fn new(
+        release_os: bool,
+        target_cpu: &'a str,
+        device_name: Option<&'a str>,
+    ) -> TargetOptions<'a> {
+        TargetOptions {
+            release_os,
+            target_cpu,
+            target_cpu_linker: target_cpu_linker(target_cpu),
+            device_name,
+            native_ssh: false,
+            device_address: None,
+            ssh_config: None,
+            control_path: None,
+        }
+    }
+
+    /// Applies the settings of the device in `registry` that the device name refers to,
+    /// or of the registry's default device if no device is named. A named device that
+    /// isn't registered gets no address or ssh config, even if these options were
+    /// resolved for another device before.
+    /// Returns whether the device is registered.
+    pub fn resolve_device(&mut self, registry: &'a DeviceRegistry) -> bool {
+        match registry.resolve(self.device_name) {
+            Some((alias, device)) => {
+                self.device_name = Some(device.nodename.as_deref().unwrap_or(alias));
+                self.device_address = device.address.as_deref();
+                self.ssh_config = device.ssh_config.as_deref();
+                if let Some(release_os) = device.is_release() {
+                    self.release_os = release_os;
+                }
+                true
+            }
+            None => {
+                if self.device_name.is_some() {
+                    self.device_address = None;
+                    self.ssh_config = None;
+                }
+                false
+            }
+        }
+    }
 
-    pub fn new(release_os: bool, device_name: Option<&'a str>) -> TargetOptions {
+    /// Returns a copy of these options targeting a different CPU.
+    pub fn with_target_cpu(&self, target_cpu: &'a str) -> TargetOptions<'a> {
         TargetOptions {
-            release_os: release_os,
-            target_cpu: "x64",
-            target_cpu_linker: "x86_64",
-            device_name: device_name,
+            target_cpu,
+            target_cpu_linker: target_cpu_linker(target_cpu),
+            ..self.clone()
+        }
+    }
+
+    /// Names the target device in the names of files fargo keeps for it, `default`
+    /// if no device was selected. A name or address that can't be used as it is in a
+    /// file name, or that would make the path of the ssh control socket too long, is
+    /// replaced by a shortened safe version of it and part of its hash.
+    pub fn device_key(&self) -> String {
+        let name = self.device_name.or(self.device_address).unwrap_or("default");
+        let is_safe = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.';
+        if name.len() <= MAX_DEVICE_KEY_LEN && name.chars().all(is_safe) {
+            return name.to_string();
         }
+        let safe_name: String = name
+            .chars()
+            .map(|c| if is_safe(c) { c } else { '_' })
+            .take(MAX_DEVICE_KEY_LEN - 9)
+            .collect();
+        let hash: String =
+            Sha256::digest(name.as_bytes()).iter().take(4).map(|b| format!("{:02x}", b)).collect();
+        format!("{}-{}", safe_name, hash)
+    }
+
+    /// The Rust target triple for the target CPU, i.e. `x86_64-unknown-fuchsia`.
+    pub fn target_triple(&self) -> String {
+        format!("{}-unknown-fuchsia", self.target_cpu_linker)
     }
 }
 
@@ -61,11 +146,7 @@ pub fn fuchsia_root(options: &TargetOptions) -> Result<PathBuf, Error> {
             path = if let Some(path) = path.parent() {
                 path.to_path_buf()
             } else {
-                bail!(
-                    "FUCHSIA_ROOT not set and current directory is not in a Fuchsia tree with a \
-                    release-x86-64 build. You must set the environmental variable FUCHSIA_ROOT to \
-                    point to a Fuchsia tree with a release-x86-64 build."
-                )
+                return Err(FargoError::FuchsiaRootNotFound(out_dir_name(options)).into());
             }
         }
     };
@@ -73,15 +154,18 @@ pub fn fuchsia_root(options: &TargetOptions) -> Result<PathBuf, Error> {
     Ok(PathBuf::from(fuchsia_root_value))
 }
 
+pub fn out_dir_name(options: &TargetOptions) -> String {
+    let out_dir_name_prefix = if options.release_os { "release" } else { "debug" };
+    format!("{}-{}", out_dir_name_prefix, options.target_cpu)
+}
+
 pub fn possible_target_out_dir(
     fuchsia_root: &PathBuf,
     options: &TargetOptions,
 ) -> Result<PathBuf, Error> {
-    let out_dir_name_prefix = if options.release_os { "release" } else { "debug" };
-    let out_dir_name = format!("{}-{}", out_dir_name_prefix, options.target_cpu);
-    let target_out_dir = fuchsia_root.join("out").join(out_dir_name);
+    let target_out_dir = fuchsia_root.join("out").join(out_dir_name(options));
     if !target_out_dir.exists() {
-        bail!("no target out directory found at  {:?}", target_out_dir);
+        return Err(FargoError::OutDirMissing(target_out_dir).into());
     }
     Ok(target_out_dir)
 }
@@ -96,10 +180,26 @@ pub fn target_gen_dir(options: &TargetOptions) -> Result<PathBuf, Error> {
     Ok(target_out_dir.join("gen"))
 }
 
-pub fn cargo_out_dir(options: &TargetOptions) -> Result<PathBuf, Error> {
-    let fuchsia_root = fuchsia_root(options)?;
-    let target_triple = format!("{}-unknown-fuchsia", options.target_cpu_linker);
-    Ok(fuchsia_root.join("garnet").join("target").join(target_triple).join("debug"))
+/// Returns the name of the directory cargo puts the outputs of `profile` in, the
+/// default `dev` profile being used when `profile` is `None`.
+fn profile_dir_name(profile: Option<&str>) -> &str {
+    match profile {
+        None | Some("dev") | Some("test") => "debug",
+        Some("bench") => "release",
+        Some(profile) => profile,
+    }
+}
+
+/// Returns the directory in which cargo puts what it builds for `options` with
+/// `profile`. `target_directory` is the one reported by `cargo metadata`, which
+/// already accounts for `CARGO_TARGET_DIR`, `build.target-dir` in `.cargo/config`
+/// and the location of the workspace.
+pub fn cargo_out_dir(
+    target_directory: &Path,
+    options: &TargetOptions,
+    profile: Option<&str>,
+) -> PathBuf {
+    target_directory.join(options.target_triple()).join(profile_dir_name(profile))
 }
 
 pub fn strip_tool_path(target_options: &TargetOptions) -> Result<PathBuf, Error> {
@@ -190,3 +290,85 @@ impl FuchsiaConfig {
         self.fuchsia_variant != "debug"
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use registry::{DeviceRegistry, RegisteredDevice};
+    use sdk::{TargetOptions, cargo_out_dir};
+    use std::path::Path;
+
+    #[test]
+    fn test_resolve_device() {
+        let mut registry = DeviceRegistry::default();
+        registry
+            .add(
+                "lab",
+                RegisteredDevice {
+                    nodename: Some(String::from("ivy-donut-grew-stoop")),
+                    address: Some(String::from("[fe80::1%eth0]:8022")),
+                    ssh_config: Some(Path::new("/lab/ssh_config").to_path_buf()),
+                    variant: Some(String::from("debug")),
+                },
+            )
+            .unwrap();
+        registry.set_default("lab").unwrap();
+
+        let mut target_options = TargetOptions::new(true, "x64", None);
+        target_options.resolve_device(&registry);
+        assert_eq!(target_options.device_name, Some("ivy-donut-grew-stoop"));
+        assert_eq!(target_options.device_address, Some("[fe80::1%eth0]:8022"));
+        assert_eq!(target_options.ssh_config, Some(Path::new("/lab/ssh_config")));
+        assert!(!target_options.release_os);
+
+        // Options resolved for the default device are reused for a device found on the
+        // network, which must not get the address of the default device.
+        let mut other_options = target_options.clone();
+        other_options.device_name = Some("step-atom-yard-juicy");
+        other_options.resolve_device(&registry);
+        assert_eq!(other_options.device_name, Some("step-atom-yard-juicy"));
+        assert_eq!(other_options.device_address, None);
+        assert_eq!(other_options.ssh_config, None);
+    }
+
+    #[test]
+    fn test_device_key() {
+        let mut target_options = TargetOptions::new(true, "x64", None);
+        assert_eq!(target_options.device_key(), "default");
+        target_options.device_name = Some("ivy-donut-grew-stoop");
+        assert_eq!(target_options.device_key(), "ivy-donut-grew-stoop");
+
+        target_options.device_name = None;
+        for address in &["[::1]:8022", "fe80::1%eth0", "192.168.42.1:8022"] {
+            target_options.device_address = Some(address);
+            let key = target_options.device_key();
+            assert!(key.len() <= 32);
+            assert!(key.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)));
+        }
+        target_options.device_address = Some("[::1]:8022");
+        let key = target_options.device_key();
+        target_options.device_address = Some("[::1]:8023");
+        assert_ne!(target_options.device_key(), key);
+    }
+
+    #[test]
+    fn test_cargo_out_dir() {
+        let target_options = TargetOptions::new(true, "arm64", None);
+        let target_directory = Path::new("/work/target");
+        assert_eq!(
+            cargo_out_dir(target_directory, &target_options, None),
+            Path::new("/work/target/aarch64-unknown-fuchsia/debug")
+        );
+        assert_eq!(
+            cargo_out_dir(target_directory, &target_options, Some("release")),
+            Path::new("/work/target/aarch64-unknown-fuchsia/release")
+        );
+        assert_eq!(
+            cargo_out_dir(target_directory, &target_options, Some("bench")),
+            Path::new("/work/target/aarch64-unknown-fuchsia/release")
+        );
+        assert_eq!(
+            cargo_out_dir(target_directory, &target_options, Some("profiling")),
+            Path::new("/work/target/aarch64-unknown-fuchsia/profiling")
+        );
+    }
+}
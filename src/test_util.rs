@@ -0,0 +1,129 @@
+// Copyright 2018 The Fuchsia Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A fake Fuchsia device for the tests of code that copies files to a device and runs
+//! commands on it.
+
+use device::Transport;
+use error::FargoError;
+use failure::{Error, err_msg};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// What running a command on the fake device does.
+pub enum FakeRun {
+    /// The command prints the output and exits with the exit code.
+    Exit(String, i32),
+    /// The connection to the device is lost after the command printed the output.
+    ConnectionLost(String),
+    /// The command can't be run for a reason other than the connection.
+    Failure,
+}
+
+#[derive(Default)]
+struct FakeDeviceState {
+    runs: VecDeque<FakeRun>,
+    commands: Vec<String>,
+    copied_files: Vec<(String, Vec<u8>)>,
+    /// Only ssh sessions have a timeout.
+    #[cfg(feature = "native-ssh")]
+    timeout_ms: u32,
+}
+
+/// A device that runs commands as scripted with `push_run`, in order, and records the
+/// commands and copied files. Clones share the same device, so that a test can keep
+/// one while the code under test connects with others.
+#[derive(Clone, Default)]
+pub struct FakeDevice {
+    state: Rc<RefCell<FakeDeviceState>>,
+}
+
+impl FakeDevice {
+    /// Scripts what the next command that isn't scripted yet does.
+    pub fn push_run(&self, run: FakeRun) {
+        self.state.borrow_mut().runs.push_back(run);
+    }
+
+    /// Returns whether every scripted command has been run.
+    pub fn all_run(&self) -> bool {
+        self.state.borrow().runs.is_empty()
+    }
+
+    pub fn commands(&self) -> Vec<String> {
+        self.state.borrow().commands.clone()
+    }
+
+    /// The destination paths and contents of the files copied to the device.
+    pub fn copied_files(&self) -> Vec<(String, Vec<u8>)> {
+        self.state.borrow().copied_files.clone()
+    }
+
+    /// Records `command` and returns what the script says it does.
+    pub fn start(&self, command: &str) -> FakeRun {
+        let mut state = self.state.borrow_mut();
+        state.commands.push(command.to_string());
+        state.runs.pop_front().unwrap_or_else(|| panic!("unexpected command {}", command))
+    }
+
+    pub fn copy(&self, destination_path: &str, contents: Vec<u8>) {
+        self.state.borrow_mut().copied_files.push((destination_path.to_string(), contents));
+    }
+
+    /// How long blocking operations on the device wait, as set with `set_timeout_ms`.
+    #[cfg(feature = "native-ssh")]
+    pub fn timeout_ms(&self) -> u32 {
+        self.state.borrow().timeout_ms
+    }
+
+    #[cfg(feature = "native-ssh")]
+    pub fn set_timeout_ms(&self, timeout_ms: u32) {
+        self.state.borrow_mut().timeout_ms = timeout_ms;
+    }
+}
+
+impl Transport for FakeDevice {
+    fn copy_to_device(&mut self, source_path: &Path, destination_path: &str) -> Result<(), Error> {
+        self.copy(destination_path, fs::read(source_path)?);
+        Ok(())
+    }
+
+    fn run(&mut self, command: &str) -> Result<i32, Error> {
+        self.run_with_output(command, &mut |_| Ok(()))
+    }
+
+    fn run_with_output(
+        &mut self,
+        command: &str,
+        on_line: &mut dyn FnMut(&str) -> Result<(), Error>,
+    ) -> Result<i32, Error> {
+        match self.start(command) {
+            FakeRun::Exit(output, exit_code) => {
+                for line in output.lines() {
+                    on_line(line)?;
+                }
+                Ok(exit_code)
+            }
+            FakeRun::ConnectionLost(output) => {
+                for line in output.lines() {
+                    on_line(line)?;
+                }
+                Err(FargoError::TransportFailed(String::from("connection reset")).into())
+            }
+            FakeRun::Failure => Err(err_msg("unable to run the command")),
+        }
+    }
+
+    fn run_with_timeout(
+        &mut self,
+        command: &str,
+        on_line: &mut dyn FnMut(&str) -> Result<(), Error>,
+        _: Duration,
+    ) -> Result<Option<i32>, Error> {
+        self.run_with_output(command, on_line).map(Some)
+    }
+}
@@ -4,25 +4,58 @@
 
 use failure::{Error, ResultExt};
 use sdk::{TargetOptions, strip_tool_path};
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::time::Duration;
 use uname::uname;
 
-#[allow(dead_code)]
 pub fn duration_as_milliseconds(duration: &Duration) -> u64 {
     let subsec_ms: u64 = u64::from(duration.subsec_nanos()) / 1_000_000;
     duration.as_secs() * 1000 + subsec_ms
 }
 
+/// Formats rows of cells as a table with aligned columns, one row per line.
+pub fn format_table(rows: &[Vec<String>]) -> String {
+    let column_count = rows.iter().map(Vec::len).max().unwrap_or(0);
+    let widths: Vec<usize> = (0..column_count)
+        .map(|column| {
+            rows.iter().filter_map(|row| row.get(column)).map(String::len).max().unwrap_or(0)
+        })
+        .collect();
+    let mut table = String::new();
+    for row in rows {
+        let cells: Vec<String> = row.iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        table.push_str(cells.join("  ").trim_end());
+        table.push('\n');
+    }
+    table
+}
+
 pub fn is_mac() -> bool {
     uname().unwrap().sysname == "Darwin"
 }
 
+/// Returns true if `path` exists and was modified no earlier than `other`. A file
+/// written in the same timestamp tick as `other` counts as up to date.
+fn is_up_to_date(path: &Path, other: &Path) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified());
+    match (modified(path), modified(other)) {
+        (Ok(path_modified), Ok(other_modified)) => path_modified >= other_modified,
+        _ => false,
+    }
+}
+
 pub fn strip_binary(binary: &PathBuf, target_options: &TargetOptions) -> Result<PathBuf, Error> {
     let file_name = binary.file_name().unwrap();
     let new_file_name = file_name.to_string_lossy().into_owned() + "_stripped";
     let target_path = binary.parent().unwrap().join(new_file_name);
+    if is_up_to_date(&target_path, binary) {
+        return Ok(target_path);
+    }
     let strip_result = Command::new(strip_tool_path(target_options)?)
         .arg("-strip-all")
         .arg(binary)